@@ -0,0 +1,184 @@
+//! What the fuzz targets do with their input, shared with `tests/fuzz_corpus.rs`,
+//! which replays the corpus without libFuzzer.
+
+use std::io::Cursor;
+use std::mem;
+use tape::compiler::Compiler;
+use tape::jit_compiler::{jit_compile, run_jit, JitCode, JitState};
+use tape::limits::{self, Limits};
+use tape::optimizer::{self, MAX_LEVEL};
+use tape::scanner::{Scanner, TokenType};
+use tape::vm::{Eof, Program, Vm, VmState};
+
+/// Longest source worth compiling, macros can make programs much longer than their source
+const MAX_SOURCE: usize = 4096;
+
+/// Opcodes a run may execute, which stops the programs that never end
+const FUEL: u64 = 100_000;
+
+const EOFS: [Eof; 4] = [Eof::Zero, Eof::MinusOne, Eof::Unchanged, Eof::Error];
+
+/// Scans the bytes as a source, which must reach EOF one token per character at most
+pub fn scanner(data: &[u8]) {
+    let source = String::from_utf8_lossy(data);
+    let mut scanner = Scanner::new(&source);
+    for _ in 0..=source.chars().count() {
+        if scanner.scan_token().typ == TokenType::EOF {
+            return;
+        }
+    }
+    panic!("no EOF scanning {:?}", source);
+}
+
+/// Compiles the bytes as a source, which may fail with an error but not panic
+pub fn compiler(data: &[u8]) {
+    if data.len() <= MAX_SOURCE {
+        compile(&String::from_utf8_lossy(data));
+    }
+}
+
+/// Runs the source before the first NUL byte on both the VM and the JIT, at
+/// every optimization level, reading what comes after it. Both backends must
+/// print the same, fail the same and leave the same tapes behind, and every
+/// level must print and fail like the unoptimized program, and leave the same
+/// tapes behind when that succeeds.
+pub fn differential(data: &[u8]) {
+    let (source, input) = match data.iter().position(|&byte| byte == 0) {
+        Some(nul) => (&data[..nul], &data[nul + 1..]),
+        None => (data, &[][..]),
+    };
+    if source.len() > MAX_SOURCE {
+        return;
+    }
+    let Some(program) = compile(&String::from_utf8_lossy(source)) else {
+        return;
+    };
+
+    for level in 0..=MAX_LEVEL {
+        let optimized = optimizer::optimize(&program, level);
+        let jit_code = jit_compile(&optimized)
+            .unwrap_or_else(|err| panic!("the JIT rejected a compiled program: {}", err));
+
+        for eof in EOFS {
+            let vm = run_vm(&optimized, input, eof, FUEL);
+            compare(&vm, &run_jit_code(&jit_code, input, eof, FUEL), eof, FUEL);
+            if level > 0 {
+                compare_levels(&run_vm(&program, input, eof, FUEL), &vm, level, eof);
+            }
+
+            // running out of fuel halfway must stop both at the same point too
+            let fuel = vm.executed / 2;
+            let vm = run_vm(&optimized, input, eof, fuel);
+            compare(&vm, &run_jit_code(&jit_code, input, eof, fuel), eof, fuel);
+        }
+    }
+}
+
+/// Compiles without reading any file, includes only find standard library modules
+fn compile(source: &str) -> Option<Program> {
+    let mut compiler = Compiler::new(source);
+    compiler.read_files = false;
+    compiler.try_compile().ok()
+}
+
+/// How a run ended and what it left behind
+#[derive(Debug)]
+struct Run {
+    result: Result<(), &'static str>,
+    output: Vec<u8>,
+    /// Every tape, or `None` for the ones the VM never made
+    tapes: Vec<Option<Vec<u8>>>,
+    /// Opcodes the VM executed
+    executed: u64,
+}
+
+fn run_limits(fuel: u64) -> Limits {
+    Limits {
+        fuel: Some(fuel),
+        max_output: Some(1 << 16),
+        max_tape: Some(1 << 16),
+        ..Limits::default()
+    }
+}
+
+fn run_vm(program: &Program, input: &[u8], eof: Eof, fuel: u64) -> Run {
+    let mut vm = Vm::with_limits(run_limits(fuel));
+    vm.set_eof(eof);
+    vm.push_input(input);
+    vm.close_input();
+
+    let result = match vm.run_for(program, u64::MAX) {
+        VmState::Halted => Ok(()),
+        VmState::Error(err) => Err(err),
+        state => panic!("the VM stopped {:?} with its input closed", state),
+    };
+    let snapshot = vm.snapshot();
+    Run {
+        result,
+        output: vm.take_output(),
+        tapes: snapshot.tapes,
+        executed: snapshot.executed,
+    }
+}
+
+fn run_jit_code(jit_code: &JitCode, input: &[u8], eof: Eof, fuel: u64) -> Run {
+    let mut output = vec![];
+    let mut state = JitState::new(Box::new(Cursor::new(input)), Box::new(&mut output));
+    state.limits = run_limits(fuel);
+    state.eof = eof;
+
+    let result = run_jit(&mut state, jit_code);
+    let tapes = mem::take(&mut state.tapes).into_iter().map(Some).collect();
+    drop(state);
+    Run {
+        result,
+        output,
+        tapes,
+        executed: 0,
+    }
+}
+
+fn compare(vm: &Run, jit: &Run, eof: Eof, fuel: u64) {
+    let context = format!("with eof {:?} and fuel {}", eof, fuel);
+    assert_eq!(vm.result, jit.result, "different results {}", context);
+    assert_eq!(vm.output, jit.output, "different output {}", context);
+
+    // the JIT charges fuel a run of opcodes at a time, so it may get further
+    if vm.result == Err(limits::FUEL_EXHAUSTED) {
+        return;
+    }
+    for (id, (vm_tape, jit_tape)) in vm.tapes.iter().zip(&jit.tapes).enumerate() {
+        if let (Some(vm_tape), Some(jit_tape)) = (vm_tape, jit_tape) {
+            assert_eq!(vm_tape, jit_tape, "different tape#{} {}", id, context);
+        }
+    }
+}
+
+/// Checks an optimized program against the unoptimized one, both on the VM
+fn compare_levels(unoptimized: &Run, optimized: &Run, level: u8, eof: Eof) {
+    // optimizing changes how much fuel a program takes
+    if unoptimized.result == Err(limits::FUEL_EXHAUSTED)
+        || optimized.result == Err(limits::FUEL_EXHAUSTED)
+    {
+        return;
+    }
+    let context = format!("at -O{} with eof {:?}", level, eof);
+    assert_eq!(
+        unoptimized.result, optimized.result,
+        "different results {}",
+        context
+    );
+    assert_eq!(
+        unoptimized.output, optimized.output,
+        "different output {}",
+        context
+    );
+    // and only a program that succeeds has to leave the same tapes behind
+    if unoptimized.result.is_ok() {
+        assert_eq!(
+            unoptimized.tapes, optimized.tapes,
+            "different tapes {}",
+            context
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tape::compiler::Compiler;
+use tape::jit_compiler::{jit_compile, run_jit, JitState};
+use tape::optimizer;
+use tape::vm::{Program, Vm, VmState};
+
+/// What one run of a program took, and did
+struct Sample {
+    compile: Duration,
+    run: Duration,
+    executed: u64,
+    output: Vec<u8>,
+}
+
+/// Summary of a measurement repeated over every run, in seconds
+struct Stats {
+    mean: f64,
+    stddev: f64,
+    min: f64,
+    median: f64,
+    max: f64,
+}
+
+impl Stats {
+    fn of(samples: impl Iterator<Item = Duration>) -> Stats {
+        let mut samples: Vec<f64> = samples.map(|sample| sample.as_secs_f64()).collect();
+        samples.sort_by(f64::total_cmp);
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = samples
+            .iter()
+            .map(|sample| (sample - mean).powi(2))
+            .sum::<f64>()
+            / (n - 1.0).max(1.0);
+        let middle = samples.len() / 2;
+        let median = if samples.len().is_multiple_of(2) {
+            (samples[middle - 1] + samples[middle]) / 2.0
+        } else {
+            samples[middle]
+        };
+        Stats {
+            mean,
+            stddev: variance.sqrt(),
+            min: samples[0],
+            median,
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// How a program did on a backend over every run
+struct Summary {
+    compile: Stats,
+    run: Stats,
+    executed: u64,
+    output: Vec<u8>,
+}
+
+/// A way of running programs, and how to compile and run one with it
+struct Backend {
+    name: &'static str,
+    /// Runs a compiled program on `input`, counting compiling it any further
+    run: fn(&Program, &[u8]) -> Result<Sample, String>,
+}
+
+const BACKENDS: [Backend; 2] = [
+    Backend {
+        name: "vm",
+        run: run_on_vm,
+    },
+    Backend {
+        name: "jit",
+        run: run_on_jit,
+    },
+];
+
+/// Runs every program `warmup + runs` times on every backend at every
+/// optimization level, without input besides `<name>.input`, and prints
+/// statistics of the last `runs`. Returns whether every program ran to the
+/// end, printing the same on every backend and level.
+pub fn run_benches(
+    programs: &[PathBuf],
+    include_dirs: &[PathBuf],
+    opt_levels: &[u8],
+    runs: usize,
+    warmup: usize,
+    out: &mut dyn Write,
+) -> io::Result<bool> {
+    let mut all_ok = true;
+    for (i, path) in programs.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        writeln!(
+            out,
+            "{}: {} runs after {} warmup",
+            path.display(),
+            runs,
+            warmup
+        )?;
+        writeln!(
+            out,
+            "{:<8} {:>22} {:>22} {:>10} {:>10} {:>10} {:>14} {:>12}",
+            "backend", "compile", "run", "min", "median", "max", "instructions", "instr/s"
+        )?;
+
+        let source_code = match fs::read_to_string(path) {
+            Ok(source_code) => source_code,
+            Err(err) => {
+                writeln!(out, "failed reading file: {}", err)?;
+                all_ok = false;
+                continue;
+            }
+        };
+        let input = fs::read(path.with_extension("input")).unwrap_or_default();
+
+        let rows = opt_levels
+            .iter()
+            .flat_map(|&level| BACKENDS.iter().map(move |backend| (backend, level)));
+        let mut expected_output = None;
+        for (backend, level) in rows {
+            let name = format!("{} -O{}", backend.name, level);
+            match bench(
+                backend,
+                level,
+                &source_code,
+                path,
+                include_dirs,
+                &input,
+                runs,
+                warmup,
+            ) {
+                Ok(Summary {
+                    compile,
+                    run,
+                    executed,
+                    output,
+                }) => {
+                    writeln!(
+                        out,
+                        "{:<8} {:>22} {:>22} {:>10} {:>10} {:>10} {:>14} {:>12}",
+                        name,
+                        format!("{} ± {}", seconds(compile.mean), seconds(compile.stddev)),
+                        format!("{} ± {}", seconds(run.mean), seconds(run.stddev)),
+                        seconds(run.min),
+                        seconds(run.median),
+                        seconds(run.max),
+                        executed,
+                        throughput(executed as f64 / run.mean)
+                    )?;
+                    let (first, expected_output) =
+                        expected_output.get_or_insert_with(|| (name.clone(), output.clone()));
+                    if output != *expected_output {
+                        writeln!(out, "{:<8} prints something else than {}", name, first)?;
+                        all_ok = false;
+                    }
+                }
+                Err(err) => {
+                    writeln!(out, "{:<8} failed: {}", name, err)?;
+                    all_ok = false;
+                }
+            }
+        }
+    }
+    Ok(all_ok)
+}
+
+/// Runs the program on `backend`, optimized to `level`, over and over
+#[allow(clippy::too_many_arguments)]
+fn bench(
+    backend: &Backend,
+    level: u8,
+    source_code: &str,
+    path: &Path,
+    include_dirs: &[PathBuf],
+    input: &[u8],
+    runs: usize,
+    warmup: usize,
+) -> Result<Summary, String> {
+    let mut samples = vec![];
+    for _ in 0..warmup + runs {
+        let started = Instant::now();
+        let mut compiler = Compiler::new(source_code);
+        compiler.path = Some(path.to_path_buf());
+        compiler.include_dirs = include_dirs.to_vec();
+        let program = compiler.try_compile().map_err(|err| err.to_string())?;
+        let program = optimizer::optimize(&program, level);
+        let compile = started.elapsed();
+
+        let mut sample = (backend.run)(&program, input)?;
+        sample.compile += compile;
+        samples.push(sample);
+    }
+    let mut samples = samples.split_off(warmup);
+    Ok(Summary {
+        compile: Stats::of(samples.iter().map(|sample| sample.compile)),
+        run: Stats::of(samples.iter().map(|sample| sample.run)),
+        executed: samples[0].executed,
+        output: mem::take(&mut samples[0].output),
+    })
+}
+
+fn run_on_vm(program: &Program, input: &[u8]) -> Result<Sample, String> {
+    let started = Instant::now();
+    let mut vm = Vm::new();
+    vm.push_input(input);
+    vm.close_input();
+    let state = vm.run_for(program, u64::MAX);
+    let run = started.elapsed();
+    match state {
+        VmState::Halted => Ok(Sample {
+            compile: Duration::ZERO,
+            run,
+            executed: vm.executed(),
+            output: vm.take_output(),
+        }),
+        VmState::Error(err) => Err(err.to_owned()),
+        _ => unreachable!("the input is closed, and there is no instruction limit"),
+    }
+}
+
+fn run_on_jit(program: &Program, input: &[u8]) -> Result<Sample, String> {
+    let started = Instant::now();
+    let jit_code = jit_compile(program)?;
+    let compile = started.elapsed();
+
+    let mut output = vec![];
+    let mut state = JitState::new(Box::new(input), Box::new(&mut output));
+    let started = Instant::now();
+    let result = run_jit(&mut state, &jit_code);
+    let run = started.elapsed();
+    let executed = state.executed();
+    drop(state);
+    result?;
+    Ok(Sample {
+        compile,
+        run,
+        executed,
+        output,
+    })
+}
+
+/// Formats a number of seconds with the unit that suits it best
+fn seconds(seconds: f64) -> String {
+    if seconds >= 1.0 {
+        format!("{:.2} s", seconds)
+    } else if seconds >= 1e-3 {
+        format!("{:.2} ms", seconds * 1e3)
+    } else if seconds >= 1e-6 {
+        format!("{:.2} µs", seconds * 1e6)
+    } else {
+        format!("{:.0} ns", seconds * 1e9)
+    }
+}
+
+/// Formats a number of instructions per second
+fn throughput(per_second: f64) -> String {
+    if per_second >= 1e9 {
+        format!("{:.2} G", per_second / 1e9)
+    } else if per_second >= 1e6 {
+        format!("{:.2} M", per_second / 1e6)
+    } else {
+        format!("{:.2} k", per_second / 1e3)
+    }
+}
@@ -1,12 +1,75 @@
-use crate::parser::Parser;
-use crate::scanner::{Scanner, TokenType};
-use crate::vm::{BracketKind, OpCode, Program};
+use crate::parser::{CompileError, Parser};
+use crate::scanner::{Expansion, Origin, Scanner, Token, TokenType};
+use crate::stdlib;
+use crate::vm::{
+    BracketKind, Cell, IdxId, NumFormat, OpCode, Program, StrId, MAX_IDXS, MAX_OFFSET,
+};
+use std::fs;
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+
+/// Most copies of an instruction `+n` makes, each one being another opcode
+const MAX_REPEAT: usize = 65_535;
+
+/// A `macro`, whose body is spliced in as tokens wherever it is used
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Token>,
+    pub line: u32,
+    /// The included file the macro is defined in, if any
+    pub defined_in: Option<Rc<Path>>,
+}
+
+/// A definition of the main file, with its doc comment, for `tape doc`
+pub struct DocItem {
+    /// The definition as written, like `macro copy(t, from, to, tmp)`
+    pub signature: String,
+    pub doc: Option<Rc<str>>,
+    pub line: u32,
+}
 
 pub struct Compiler {
     pub parser: Parser,
     pub program: Vec<OpCode>,
-    pub tape_name: String,
-    pub idx_name: String,
+    pub lines: Vec<u32>,
+    /// Names of the tapes made so far, indexed by `TapeId`
+    pub tapes: Vec<String>,
+    /// Sizes of those tapes, for checking fixed positions against
+    pub tape_sizes: Vec<usize>,
+    /// Names of the index variables made so far, indexed by `IdxId`
+    pub idxs: Vec<String>,
+    /// Names given to fixed cell positions by `alias`
+    pub aliases: Vec<(String, usize)>,
+    /// Bytes of the string literals printed so far, indexed by `StrId`
+    pub strings: Vec<Vec<u8>>,
+    /// Names of the procs defined so far, with the index of their first opcode
+    pub procs: Vec<(String, usize)>,
+    /// `Call`s waiting for the end of the program to find their proc
+    calls: Vec<(usize, Token)>,
+    pub macros: Vec<Macro>,
+    /// The file being compiled, which includes are relative to.
+    /// Without it they are relative to the current directory.
+    pub path: Option<PathBuf>,
+    /// Where to look for included files not found next to the file including them
+    pub include_dirs: Vec<PathBuf>,
+    /// Whether includes may read files, or only find standard library modules
+    pub read_files: bool,
+    /// Every file included so far, which later includes of them skip
+    included: Vec<PathBuf>,
+    /// Included files opcodes were emitted from, and the index in it of every opcode
+    files: Vec<PathBuf>,
+    op_files: Vec<Option<usize>>,
+    /// Every `make`, `proc` and `macro` of the main file
+    pub docs: Vec<DocItem>,
+    loop_depth: usize,
+    if_depth: usize,
+    in_proc: bool,
+    /// The length of the program right after the last loop, if, proc or
+    /// `expect_output`, none of which `+n` can repeat
+    block_end: Option<usize>,
 }
 
 impl Compiler {
@@ -14,157 +77,953 @@ impl Compiler {
         Self {
             parser: Parser::new(Scanner::new(code)),
             program: vec![],
-            tape_name: "tape".to_owned(),
-            idx_name: "idx".to_owned(),
+            lines: vec![],
+            tapes: vec![],
+            tape_sizes: vec![],
+            idxs: vec![],
+            aliases: vec![],
+            strings: vec![],
+            procs: vec![],
+            calls: vec![],
+            macros: vec![],
+            path: None,
+            include_dirs: vec![],
+            read_files: true,
+            included: vec![],
+            files: vec![],
+            op_files: vec![],
+            docs: vec![],
+            loop_depth: 0,
+            if_depth: 0,
+            in_proc: false,
+            block_end: None,
         }
     }
 
     fn emit(&mut self, op: OpCode) {
         self.program.push(op);
+        self.lines.push(self.parser.previous.line);
+
+        let file = self
+            .parser
+            .previous
+            .expansion
+            .as_ref()
+            .and_then(|e| e.file());
+        let file = file.map(|file| match self.files.iter().position(|f| **f == **file) {
+            Some(index) => index,
+            None => {
+                self.files.push(file.to_path_buf());
+                self.files.len() - 1
+            }
+        });
+        self.op_files.push(file);
     }
 
-    fn make_tape_variable(&mut self) {
-        self.parser.consume(TokenType::Ident);
-        let var_name = self.parser.previous.clone().lexeme;
+    /// Errors on `keyword` unless it is outside of every loop, if and proc
+    fn top_level_only(&self, keyword: &str) -> Result<(), CompileError> {
+        let inside = if self.loop_depth > 0 {
+            "a loop"
+        } else if self.if_depth > 0 {
+            "an if"
+        } else if self.in_proc {
+            "a proc"
+        } else {
+            return Ok(());
+        };
+        self.parser.error_at(
+            &self.parser.previous,
+            &format!("`{}` is not allowed inside {}", keyword, inside),
+        )
+    }
 
-        if !self.parser.matches(TokenType::LeftBrace) {
-            self.default_make_tape();
-            self.parser.consume(TokenType::Colon);
-            self.idx_name = var_name;
-            self.parser.consume_fixed(TokenType::Ident, "idx");
-            return;
+    /// Keeps the doc comment of a definition starting with `keyword`, if it is in the main file
+    fn document(&mut self, keyword: &Token, signature: String) {
+        if keyword.expansion.is_none() {
+            self.docs.push(DocItem {
+                signature,
+                doc: keyword.doc.clone(),
+                line: keyword.line,
+            });
         }
+    }
 
-        self.parser.consume(TokenType::Number);
+    fn make_stmt(&mut self) -> Result<(), CompileError> {
+        self.top_level_only("make")?;
+        let keyword = self.parser.previous.clone();
+
+        self.parser.consume(TokenType::Ident)?;
+        let name = self.parser.previous.clone();
+        let var_name = name.lexeme.clone();
+
+        let signature = if self.parser.matches(TokenType::LeftBrace)? {
+            self.make_tape_variable(&name)?;
+            format!(
+                "make {}[{}]",
+                var_name,
+                self.tape_sizes[self.tape_sizes.len() - 1]
+            )
+        } else {
+            // the first tape is the default one unless the program starts by making its own
+            if self.tapes.is_empty() {
+                self.default_make_tape();
+            }
+            self.make_idx_variable(&name)?;
+            format!("make {}: idx", var_name)
+        };
+        self.document(&keyword, signature);
+        Ok(())
+    }
+
+    fn make_tape_variable(&mut self, name: &Token) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Number)?;
         let num_token = self.parser.previous.clone();
         let num = match num_token.lexeme.parse::<usize>() {
             Ok(num) => num,
-            Err(_) => self.parser.error_at_current("Could not parse number"),
+            Err(_) => return self.parser.error_at(&num_token, "Could not parse number"),
         };
 
-        self.tape_name = var_name;
-        self.parser.consume(TokenType::RightBrace);
-        self.emit(OpCode::MakeTape(num));
+        if self.is_defined(&name.lexeme) {
+            return self
+                .parser
+                .error_at(name, &format!("`{}` already defined", &name.lexeme));
+        }
+
+        self.parser.consume(TokenType::RightBrace)?;
+        self.tapes.push(name.lexeme.clone());
+        self.tape_sizes.push(num);
+        self.emit(OpCode::MakeTape(self.tapes.len() - 1, num));
+        Ok(())
     }
 
     fn default_make_tape(&mut self) {
-        self.emit(OpCode::MakeTape(30_000));
+        self.tapes.push("tape".to_owned());
+        self.tape_sizes.push(30_000);
+        self.emit(OpCode::MakeTape(0, 30_000));
+    }
+
+    fn make_idx_variable(&mut self, name: &Token) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Colon)?;
+        if self.is_defined(&name.lexeme) {
+            return self
+                .parser
+                .error_at(name, &format!("`{}` already defined", &name.lexeme));
+        }
+        if self.idxs.len() == MAX_IDXS {
+            return self.parser.error_at(
+                name,
+                &format!("A program can make at most {} indices", MAX_IDXS),
+            );
+        }
+        self.idxs.push(name.lexeme.clone());
+        self.parser.consume_fixed(TokenType::Ident, "idx")?;
+        Ok(())
+    }
+
+    /// Whether `name` is taken by a tape, an index variable or an alias
+    fn is_defined(&self, name: &str) -> bool {
+        self.tapes.iter().any(|tape| tape == name)
+            || self.idxs.iter().any(|idx| idx == name)
+            || self.alias(name).is_some()
     }
 
-    fn make_tape_decl(&mut self) {
-        if self.parser.matches(TokenType::Make) {
-            self.make_tape_variable();
+    /// Looks up an index variable, where `idx` names the first one until a program makes its own
+    fn idx_index(&self, name: &str) -> Option<IdxId> {
+        if self.idxs.is_empty() {
+            (name == "idx").then_some(0)
         } else {
-            self.default_make_tape();
+            self.idxs.iter().position(|idx| idx == name)
         }
     }
 
-    fn make_idx_variable(&mut self) {
-        self.parser.consume(TokenType::Ident);
-        let var_name = self.parser.previous.clone().lexeme;
+    fn idx(&mut self, name: &Token) -> Result<IdxId, CompileError> {
+        match self.idx_index(&name.lexeme) {
+            Some(id) => Ok(id),
+            None => self
+                .parser
+                .error_at(name, &format!("`{}` not defined", &name.lexeme)),
+        }
+    }
 
-        self.parser.consume(TokenType::Colon);
-        self.idx_name = var_name;
-        self.parser.consume_fixed(TokenType::Ident, "idx");
+    /// The position an `alias` gave to `name`
+    fn alias(&self, name: &str) -> Option<usize> {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias == name)
+            .map(|&(_, position)| position)
     }
 
-    fn make_idx_decl(&mut self) {
-        if self.parser.matches(TokenType::Make) {
-            self.make_idx_variable();
+    /// Parses the `idx]` or `alias]` following `tape[`, either one
+    /// optionally followed by `+ n` or `- n`
+    fn cell(&mut self, tape: &Token) -> Result<Cell, CompileError> {
+        let Some(id) = self.tapes.iter().position(|name| *name == tape.lexeme) else {
+            return self
+                .parser
+                .error_at(tape, &format!("`{}` not defined", &tape.lexeme));
+        };
+
+        self.parser.consume(TokenType::Ident)?;
+        let name = self.parser.previous.clone();
+        let cell = match self.alias(&name.lexeme) {
+            Some(position) => Cell::at(id, position),
+            None => Cell::under(id, self.idx(&name)?),
+        };
+        let offset = self.offset_operand()?;
+        let Some(cell) = cell.offset_by(offset) else {
+            return self.parser.error_at(
+                &self.parser.previous,
+                &format!(
+                    "`{}{:+}` is more than {} cells away",
+                    name.lexeme, offset, MAX_OFFSET
+                ),
+            );
+        };
+
+        // fixed positions are known, so they are checked right away
+        if cell.idx.is_none() && !(0..self.tape_sizes[id] as isize).contains(&cell.offset) {
+            return self.parser.error_at(
+                &name,
+                &format!(
+                    "`{}` ({}) is outside of `{}`",
+                    name.lexeme, cell.offset, tape.lexeme
+                ),
+            );
         }
+
+        self.parser.consume(TokenType::RightBrace)?;
+        Ok(cell)
     }
 
-    fn incr_stmt(&mut self) {
-        self.parser.consume(TokenType::Ident);
-        let ident = self.parser.previous.clone();
+    /// Parses the optional `+ n` or `- n` of a cell
+    fn offset_operand(&mut self) -> Result<isize, CompileError> {
+        let sign = if self.parser.matches(TokenType::Plus)? {
+            1
+        } else if self.parser.matches(TokenType::Minus)? {
+            -1
+        } else {
+            return Ok(0);
+        };
+
+        self.parser.consume(TokenType::Number)?;
+        let num_token = self.parser.previous.clone();
+        match num_token.lexeme.parse::<isize>() {
+            Ok(num) => Ok(sign * num),
+            Err(_) => self.parser.error_at(&num_token, "Could not parse number"),
+        }
+    }
 
-        if self.parser.matches(TokenType::LeftBrace) {
-            if self.tape_name != ident.lexeme {
-                self.parser
-                    .error_at_current(&format!("`{}` not defined", &ident.lexeme));
+    /// Parses a cell position, as a number or an alias, checking that some tape has it
+    fn position_operand(&mut self) -> Result<usize, CompileError> {
+        let token = self.parser.current.clone();
+        let position = if self.parser.matches(TokenType::Ident)? {
+            match self.alias(&token.lexeme) {
+                Some(position) => position,
+                None => {
+                    return self
+                        .parser
+                        .error_at(&token, &format!("`{}` is not an alias", &token.lexeme))
+                }
+            }
+        } else {
+            self.parser.consume(TokenType::Number)?;
+            match token.lexeme.parse::<usize>() {
+                Ok(num) => num,
+                Err(_) => return self.parser.error_at(&token, "Could not parse number"),
             }
+        };
 
-            self.parser.consume(TokenType::Ident);
-            let idx = self.parser.previous.clone();
+        if !self.tape_sizes.iter().any(|&size| position < size) {
+            return self.parser.error_at(
+                &token,
+                &format!("{} is past the end of every tape", position),
+            );
+        }
+        Ok(position)
+    }
 
-            if self.idx_name != idx.lexeme {
-                self.parser
-                    .error_at_current(&format!("`{}` not defined", &idx.lexeme));
+    fn alias_stmt(&mut self) -> Result<(), CompileError> {
+        self.top_level_only("alias")?;
+
+        self.parser.consume(TokenType::Ident)?;
+        let name = self.parser.previous.clone();
+        if self.is_defined(&name.lexeme) {
+            return self
+                .parser
+                .error_at(&name, &format!("`{}` already defined", &name.lexeme));
+        }
+        self.parser.consume(TokenType::Equal)?;
+        let position = self.position_operand()?;
+        self.aliases.push((name.lexeme, position));
+        Ok(())
+    }
+
+    /// `goto [idx] position` moves an index, the first one by default, straight to a cell
+    fn goto_stmt(&mut self) -> Result<(), CompileError> {
+        let idx = match self.parser.current.clone() {
+            token if token.typ == TokenType::Ident && self.idx_index(&token.lexeme).is_some() => {
+                self.parser.advance()?;
+                self.idx(&token)?
             }
+            _ => 0,
+        };
+        let position = self.position_operand()?;
+        self.emit(OpCode::SetPtr(idx, position));
+        Ok(())
+    }
+
+    /// The index variable starting an `idx = position` statement, if any
+    fn assigned_idx(&self) -> Option<IdxId> {
+        let token = &self.parser.current;
+        if token.typ != TokenType::Ident {
+            return None;
+        }
+        self.idx_index(&token.lexeme)
+    }
+
+    /// `idx = position`, the same as `goto idx position`
+    fn assign_stmt(&mut self, idx: IdxId) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Equal)?;
+        let position = self.position_operand()?;
+        self.emit(OpCode::SetPtr(idx, position));
+        Ok(())
+    }
+
+    /// Parses the optional `tape[idx]` operand of `putch`, `getch` and `loop`,
+    /// which defaults to the first tape
+    fn cell_operand(&mut self) -> Result<Cell, CompileError> {
+        // an identifier that neither names a tape nor comes before `[`
+        // starts the next statement instead, like a macro or `ptr = 3`
+        let current = &self.parser.current;
+        let is_operand = current.typ == TokenType::Ident
+            && (self.tapes.contains(&current.lexeme)
+                || self.parser.peek().typ == TokenType::LeftBrace);
+        if !is_operand {
+            return Ok(Cell::default());
+        }
+        self.parser.advance()?;
+        let tape = self.parser.previous.clone();
+        self.parser.consume(TokenType::LeftBrace)?;
+        self.cell(&tape)
+    }
+
+    /// The character of the char literal just parsed
+    fn char_literal(&self) -> Result<char, CompileError> {
+        let token = &self.parser.previous;
+        let chars: Vec<char> = token.lexeme.chars().collect();
+        let c = match chars[1..chars.len() - 1] {
+            ['\\', c] => unescape(c),
+            [c] => Some(c),
+            _ => None,
+        };
+        match c {
+            Some(c) => Ok(c),
+            None => self
+                .parser
+                .error_at(token, &format!("Unknown escape in {}", token.lexeme)),
+        }
+    }
+
+    /// Parses a number or char literal that fits in a cell
+    fn byte_operand(&mut self) -> Result<u8, CompileError> {
+        if self.parser.matches(TokenType::Char)? {
+            let c = self.char_literal()?;
+            // a cell holds the code points up to U+00FF, which `putu` prints as UTF-8
+            let Ok(byte) = u8::try_from(c) else {
+                let token = &self.parser.previous;
+                return self.parser.error_at(
+                    token,
+                    &format!(
+                        "{} does not fit in a cell, which holds characters up to U+00FF",
+                        token.lexeme
+                    ),
+                );
+            };
+            return Ok(byte);
+        }
+
+        self.parser.consume(TokenType::Number)?;
+        let num_token = self.parser.previous.clone();
+        match num_token.lexeme.parse::<u8>() {
+            Ok(num) => Ok(num),
+            Err(_) => self.parser.error_at(
+                &num_token,
+                &format!("{} does not fit in a cell", num_token.lexeme),
+            ),
+        }
+    }
 
-            self.parser.consume(TokenType::RightBrace);
-            self.emit(OpCode::IncrCell);
+    /// Parses the optional `by n` of `incr` and `decr` on a cell
+    fn by_operand(&mut self) -> Result<u8, CompileError> {
+        if self.parser.matches(TokenType::By)? {
+            self.byte_operand()
         } else {
-            if self.idx_name != ident.lexeme {
-                self.parser
-                    .error_at_current(&format!("`{}` not defined", &ident.lexeme));
-            }
+            Ok(1)
+        }
+    }
 
-            self.emit(OpCode::IncrPtr);
+    fn incr_stmt(&mut self) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Ident)?;
+        let ident = self.parser.previous.clone();
+
+        if self.parser.matches(TokenType::LeftBrace)? {
+            let cell = self.cell(&ident)?;
+            let n = self.by_operand()?;
+            self.emit(OpCode::IncrCell(cell, n));
+        } else {
+            let idx = self.idx(&ident)?;
+            if self.parser.check(TokenType::By) {
+                return self.parser.error_at_current("`by` only applies to cells");
+            }
+            self.emit(OpCode::IncrPtr(idx));
         }
+        Ok(())
     }
 
-    fn decr_stmt(&mut self) {
-        self.parser.consume(TokenType::Ident);
+    fn decr_stmt(&mut self) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Ident)?;
         let ident = self.parser.previous.clone();
 
-        if self.parser.matches(TokenType::LeftBrace) {
-            if self.tape_name != ident.lexeme {
-                self.parser
-                    .error_at_current(&format!("`{}` not defined", &ident.lexeme));
+        if self.parser.matches(TokenType::LeftBrace)? {
+            let cell = self.cell(&ident)?;
+            let n = self.by_operand()?;
+            self.emit(OpCode::DecrCell(cell, n));
+        } else {
+            let idx = self.idx(&ident)?;
+            if self.parser.check(TokenType::By) {
+                return self.parser.error_at_current("`by` only applies to cells");
             }
+            self.emit(OpCode::DecrPtr(idx));
+        }
+        Ok(())
+    }
 
-            self.parser.consume(TokenType::Ident);
-            let idx = self.parser.previous.clone();
+    fn set_stmt(&mut self) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Ident)?;
+        let tape = self.parser.previous.clone();
+        self.parser.consume(TokenType::LeftBrace)?;
+        let cell = self.cell(&tape)?;
+        // a character past U+00FF is spread over three cells, as `putu wide` reads it
+        if self.parser.matches(TokenType::Char)? {
+            let c = self.char_literal()?;
+            if let Ok(byte) = u8::try_from(c) {
+                self.emit(OpCode::SetCell(cell, byte));
+            } else {
+                self.wide_cell(cell)?;
+                for (i, &byte) in (c as u32).to_le_bytes()[..3].iter().enumerate() {
+                    let cell = cell.offset_by(i as isize).expect("checked by `wide_cell`");
+                    self.emit(OpCode::SetCell(cell, byte));
+                }
+            }
+            return Ok(());
+        }
+        let n = self.byte_operand()?;
+        self.emit(OpCode::SetCell(cell, n));
+        Ok(())
+    }
 
-            if self.idx_name != idx.lexeme {
-                self.parser
-                    .error_at_current(&format!("`{}` not defined", &idx.lexeme));
+    /// Checks that the two cells after `cell`, which a wide character also
+    /// takes, are not too far away, nor past the end of the tape when fixed
+    fn wide_cell(&self, cell: Cell) -> Result<(), CompileError> {
+        let err = match cell.offset_by(2) {
+            None => format!(
+                "A wide character takes 3 cells, and the last one is more than {} cells away",
+                MAX_OFFSET
+            ),
+            Some(last)
+                if last.idx.is_none() && last.offset >= self.tape_sizes[cell.tape] as isize =>
+            {
+                format!(
+                    "A wide character takes 3 cells, and the last one is outside of `{}`",
+                    self.tapes[cell.tape]
+                )
             }
+            Some(_) => return Ok(()),
+        };
+        self.parser.error_at(&self.parser.previous, &err)
+    }
 
-            self.parser.consume(TokenType::RightBrace);
-            self.emit(OpCode::DecrCell);
+    fn putch_stmt(&mut self) -> Result<(), CompileError> {
+        let cell = self.cell_operand()?;
+        self.emit(OpCode::PrintChar(cell));
+        Ok(())
+    }
+
+    /// `putnum [hex] [tape[idx]]`, where `hex` is the format unless a tape has that name
+    fn putnum_stmt(&mut self) -> Result<(), CompileError> {
+        let current = &self.parser.current;
+        let format = if current.typ == TokenType::Ident
+            && current.lexeme == "hex"
+            && !self.tapes.contains(&current.lexeme)
+        {
+            self.parser.advance()?;
+            NumFormat::Hex
         } else {
-            if self.idx_name != ident.lexeme {
-                self.parser
-                    .error_at_current(&format!("`{}` not defined", &ident.lexeme));
-            }
+            NumFormat::Decimal
+        };
+        let cell = self.cell_operand()?;
+        self.emit(OpCode::PrintNum(cell, format));
+        Ok(())
+    }
 
-            self.emit(OpCode::DecrPtr);
+    /// `putu [wide] [tape[idx]]` writes a cell as the character with its value
+    /// as code point, encoded in UTF-8. `wide` reads the code point from the
+    /// cell and the two after it instead, for the characters past U+00FF.
+    fn putu_stmt(&mut self) -> Result<(), CompileError> {
+        let current = &self.parser.current;
+        let wide = current.typ == TokenType::Ident
+            && current.lexeme == "wide"
+            && !self.tapes.contains(&current.lexeme);
+        if wide {
+            self.parser.advance()?;
         }
+        let cell = self.cell_operand()?;
+        if wide {
+            self.wide_cell(cell)?;
+            self.emit(OpCode::PrintWideUtf8(cell));
+        } else {
+            self.emit(OpCode::PrintUtf8(cell));
+        }
+        Ok(())
     }
 
-    fn putch_stmt(&mut self) {
-        self.emit(OpCode::PrintChar);
+    fn getnum_stmt(&mut self) -> Result<(), CompileError> {
+        let cell = self.cell_operand()?;
+        self.emit(OpCode::GetNum(cell));
+        Ok(())
     }
 
-    fn getch_stmt(&mut self) {
-        self.emit(OpCode::GetChar);
+    fn print_stmt(&mut self) -> Result<(), CompileError> {
+        let id = self.string_operand()?;
+        self.emit(OpCode::Print(id));
+        Ok(())
     }
 
-    fn loop_block(&mut self) {
+    /// A string literal, with its escapes replaced
+    fn string_operand(&mut self) -> Result<StrId, CompileError> {
+        self.parser.consume(TokenType::String)?;
+        let token = self.parser.previous.clone();
+        let lexeme = token.lexeme.clone();
+        let mut string = String::new();
+        let mut chars = lexeme[1..lexeme.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            let c = match c {
+                '\\' => chars.next().and_then(unescape),
+                c => Some(c),
+            };
+            match c {
+                Some(c) => string.push(c),
+                None => {
+                    return self
+                        .parser
+                        .error_at(&token, &format!("Unknown escape in {}", lexeme))
+                }
+            }
+        }
+
+        Ok(self.string(string.into_bytes()))
+    }
+
+    /// `assert tape[ptr] == 5` fails the program unless the cell holds the value
+    fn assert_stmt(&mut self) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Ident)?;
+        let tape = self.parser.previous.clone();
+        self.parser.consume(TokenType::LeftBrace)?;
+        let cell = self.cell(&tape)?;
+        self.parser.consume(TokenType::EqualEqual)?;
+        let n = self.byte_operand()?;
+        self.emit(OpCode::Assert(cell, n));
+        Ok(())
+    }
+
+    /// `expect_output "..." ( ... )` fails the program unless the block writes the string
+    fn expect_output_stmt(&mut self) -> Result<(), CompileError> {
+        let id = self.string_operand()?;
+        self.parser.consume(TokenType::LeftParen)?;
+        self.emit(OpCode::CaptureOutput);
+        self.loop_block()?;
+        self.emit(OpCode::ExpectOutput(id));
+        Ok(())
+    }
+
+    /// Adds a string to the program, reusing an identical one
+    fn string(&mut self, bytes: Vec<u8>) -> StrId {
+        match self.strings.iter().position(|string| *string == bytes) {
+            Some(id) => id,
+            None => {
+                self.strings.push(bytes);
+                self.strings.len() - 1
+            }
+        }
+    }
+
+    fn getch_stmt(&mut self) -> Result<(), CompileError> {
+        let cell = self.cell_operand()?;
+        self.emit(OpCode::GetChar(cell));
+        Ok(())
+    }
+
+    fn loop_block(&mut self) -> Result<(), CompileError> {
         while !self.parser.check(TokenType::RightParen) && !self.parser.check(TokenType::EOF) {
-            self.statement();
+            self.statement()?;
         }
 
-        self.parser.consume(TokenType::RightParen);
+        self.parser.consume(TokenType::RightParen)?;
+        Ok(())
     }
 
-    fn loop_stmt(&mut self) {
-        self.parser.consume(TokenType::LeftParen);
+    fn loop_stmt(&mut self) -> Result<(), CompileError> {
+        let cell = self.cell_operand()?;
+        self.parser.consume(TokenType::LeftParen)?;
         let loop_start = self.program.len();
 
-        let exit_jump = self.emit_jump(OpCode::Jump(0, BracketKind::Close));
-        self.loop_block();
+        let exit_jump = self.emit_jump(OpCode::Jump(0, BracketKind::Close, cell));
+        self.loop_depth += 1;
+        self.loop_block()?;
+        self.loop_depth -= 1;
 
-        self.emit_loop(loop_start);
+        self.emit_loop(loop_start, cell);
         self.patch_jump(exit_jump);
+        Ok(())
+    }
+
+    fn if_stmt(&mut self) -> Result<(), CompileError> {
+        let cell = self.cell_operand()?;
+        self.parser.consume(TokenType::LeftParen)?;
+
+        let then_jump = self.emit_jump(OpCode::BranchIfZero(0, cell));
+        self.if_depth += 1;
+        self.loop_block()?;
+
+        if self.parser.matches(TokenType::Else)? {
+            let else_jump = self.emit_jump(OpCode::Branch(0));
+            self.patch_jump(then_jump);
+            self.parser.consume(TokenType::LeftParen)?;
+            self.loop_block()?;
+            self.patch_jump(else_jump);
+        } else {
+            self.patch_jump(then_jump);
+        }
+        self.if_depth -= 1;
+        Ok(())
     }
 
-    fn emit_loop(&mut self, loop_start: usize) {
+    fn emit_loop(&mut self, loop_start: usize, cell: Cell) {
         //let offset = self.program.len() - loop_start + 1;
         let offset = self.program.len() - loop_start;
-        self.emit(OpCode::Jump(offset, BracketKind::Open));
+        self.emit(OpCode::Jump(offset, BracketKind::Open, cell));
+    }
+
+    fn proc_stmt(&mut self) -> Result<(), CompileError> {
+        self.top_level_only("proc")?;
+        let keyword = self.parser.previous.clone();
+
+        self.parser.consume(TokenType::Ident)?;
+        let name = self.parser.previous.clone();
+        if self.procs.iter().any(|(proc, _)| *proc == name.lexeme) {
+            return self
+                .parser
+                .error_at(&name, &format!("`{}` already defined", &name.lexeme));
+        }
+
+        self.document(&keyword, format!("proc {}", name.lexeme));
+
+        self.parser.consume(TokenType::LeftParen)?;
+        let skip_jump = self.emit_jump(OpCode::SkipProc(0));
+        // registered before the body so a proc can call itself
+        self.procs.push((name.lexeme, self.program.len()));
+
+        self.in_proc = true;
+        self.loop_block()?;
+        self.in_proc = false;
+
+        self.emit(OpCode::Return);
+        self.patch_jump(skip_jump);
+        Ok(())
+    }
+
+    fn call_stmt(&mut self) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Ident)?;
+        let name = self.parser.previous.clone();
+        // procs may be defined after their calls, so targets are filled in by `resolve_calls`
+        let call = self.emit_jump(OpCode::Call(0));
+        self.calls.push((call, name));
+        Ok(())
+    }
+
+    fn resolve_calls(&mut self) -> Result<(), CompileError> {
+        for (call, name) in mem::take(&mut self.calls) {
+            let Some(&(_, target)) = self.procs.iter().find(|(proc, _)| *proc == name.lexeme)
+            else {
+                return self
+                    .parser
+                    .error_at(&name, &format!("`{}` not defined", &name.lexeme));
+            };
+            self.program[call] = OpCode::Call(target);
+        }
+        Ok(())
+    }
+
+    fn macro_stmt(&mut self) -> Result<(), CompileError> {
+        self.top_level_only("macro")?;
+        let keyword = self.parser.previous.clone();
+
+        self.parser.consume(TokenType::Ident)?;
+        let name = self.parser.previous.clone();
+        if self.macros.iter().any(|m| m.name == name.lexeme) {
+            return self
+                .parser
+                .error_at(&name, &format!("`{}` already defined", &name.lexeme));
+        }
+
+        self.parser.consume(TokenType::LeftParen)?;
+        let mut params: Vec<String> = vec![];
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                self.parser.consume(TokenType::Ident)?;
+                let param = self.parser.previous.lexeme.clone();
+                if params.contains(&param) {
+                    return self.parser.error_at(
+                        &self.parser.previous,
+                        &format!("`{}` already defined", &param),
+                    );
+                }
+                params.push(param);
+                if !self.parser.matches(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.parser.consume(TokenType::RightParen)?;
+        self.document(
+            &keyword,
+            format!("macro {}({})", name.lexeme, params.join(", ")),
+        );
+
+        // the body is kept as tokens up to the matching `)`, and compiled at each use
+        self.parser.consume(TokenType::LeftParen)?;
+        let mut body = vec![];
+        let mut depth = 0;
+        loop {
+            match self.parser.current.typ {
+                TokenType::EOF => {
+                    return self
+                        .parser
+                        .error_at_current("Expected `)` closing the macro")
+                }
+                // anything a macro defined would be defined again at its next use
+                TokenType::Macro
+                | TokenType::Include
+                | TokenType::Alias
+                | TokenType::Make
+                | TokenType::Proc => {
+                    let keyword = &self.parser.current.lexeme;
+                    return self
+                        .parser
+                        .error_at_current(&format!("`{}` is not allowed inside a macro", keyword));
+                }
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen if depth == 0 => break,
+                TokenType::RightParen => depth -= 1,
+                _ => {}
+            }
+            body.push(self.parser.current.clone());
+            self.parser.advance()?;
+        }
+        self.parser.advance()?;
+
+        self.macros.push(Macro {
+            name: name.lexeme,
+            params,
+            body,
+            line: name.line,
+            defined_in: name
+                .expansion
+                .as_ref()
+                .and_then(|expansion| expansion.file().cloned()),
+        });
+        Ok(())
+    }
+
+    fn macro_use(&mut self, index: usize) -> Result<(), CompileError> {
+        let name = self.parser.previous.clone();
+        self.parser.consume(TokenType::LeftParen)?;
+        let mut args = vec![];
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                match self.parser.current.typ {
+                    TokenType::Number | TokenType::Char | TokenType::String | TokenType::Ident => {
+                        args.push(self.parser.current.clone());
+                        self.parser.advance()?;
+                    }
+                    _ => return self.parser.error_at_current("Expected a macro argument"),
+                }
+                if !self.parser.matches(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.parser.consume(TokenType::RightParen)?;
+
+        let mac = &self.macros[index];
+        if args.len() != mac.params.len() {
+            return self.parser.error_at(
+                &name,
+                &format!(
+                    "`{}` takes {} arguments, {} given",
+                    &mac.name,
+                    mac.params.len(),
+                    args.len()
+                ),
+            );
+        }
+
+        // a macro using itself, even through others, would never stop expanding
+        let mut expansion = name.expansion.as_deref();
+        while let Some(used) = expansion {
+            if matches!(&used.origin, Origin::Macro { name, .. } if *name == mac.name) {
+                return self
+                    .parser
+                    .error_at(&name, &format!("`{}` uses itself", &mac.name));
+            }
+            expansion = used.parent.as_deref();
+        }
+
+        let used = Rc::new(Expansion {
+            origin: Origin::Macro {
+                name: mac.name.clone(),
+                defined_at: mac.line,
+                defined_in: mac.defined_in.clone(),
+            },
+            used_at: name.line,
+            parent: name.expansion.clone(),
+        });
+        // arguments are substituted as single tokens and keep pointing at the use site
+        let tokens = mac
+            .body
+            .iter()
+            .map(|token| {
+                let param = mac
+                    .params
+                    .iter()
+                    .position(|param| token.typ == TokenType::Ident && *param == token.lexeme);
+                match param {
+                    Some(i) => args[i].clone(),
+                    None => Token {
+                        expansion: Some(used.clone()),
+                        ..token.clone()
+                    },
+                }
+            })
+            .collect();
+        self.parser.expand(tokens);
+        Ok(())
+    }
+
+    fn include_stmt(&mut self) -> Result<(), CompileError> {
+        self.top_level_only("include")?;
+
+        self.parser.consume(TokenType::String)?;
+        let token = self.parser.previous.clone();
+        let name = &token.lexeme[1..token.lexeme.len() - 1];
+        // only files under the directories searched may be included
+        let inside = Path::new(name)
+            .components()
+            .all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+        if !inside {
+            return self.parser.error_at(
+                &token,
+                &format!("Cannot include `{}`, which is not a relative path inside the include directories", name),
+            );
+        }
+        let Some((path, source)) = self.find_include(&token, name) else {
+            return self
+                .parser
+                .error_at(&token, &format!("Could not find `{}` to include", name));
+        };
+
+        // including a file that is still being included would never end
+        let main = self.path.as_ref().and_then(|main| main.canonicalize().ok());
+        if main.as_ref() == Some(&path) {
+            return self
+                .parser
+                .error_at(&token, &format!("`{}` includes itself", name));
+        }
+        let mut expansion = token.expansion.as_deref();
+        while let Some(used) = expansion {
+            if matches!(&used.origin, Origin::Include { path: including } if **including == *path) {
+                return self
+                    .parser
+                    .error_at(&token, &format!("`{}` includes itself", name));
+            }
+            expansion = used.parent.as_deref();
+        }
+        if self.included.contains(&path) {
+            return Ok(());
+        }
+        self.included.push(path.clone());
+        let path: Rc<Path> = path.into();
+        self.parser
+            .sources
+            .insert(path.clone(), source.as_str().into());
+
+        let used = Some(Rc::new(Expansion {
+            origin: Origin::Include { path },
+            used_at: token.line,
+            parent: token.expansion.clone(),
+        }));
+        let mut scanner = Scanner::new(&source);
+        let mut tokens = vec![];
+        loop {
+            let token = scanner.scan_token();
+            if token.typ == TokenType::EOF {
+                break;
+            }
+            tokens.push(Token {
+                expansion: used.clone(),
+                ..token
+            });
+        }
+        self.parser.expand(tokens);
+        Ok(())
+    }
+
+    /// Looks for `name` next to the file including it, then in the include
+    /// directories, then in the standard library, skipping any file a symlink
+    /// leads out of the directory it is found in
+    fn find_include(&self, token: &Token, name: &str) -> Option<(PathBuf, String)> {
+        let including = match token.expansion.as_ref().and_then(|e| e.file()) {
+            Some(path) => Some(path.to_path_buf()),
+            None => self.path.clone(),
+        };
+        let here = including
+            .as_ref()
+            .and_then(|path| path.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+
+        if self.read_files {
+            for dir in [here].iter().chain(&self.include_dirs) {
+                let path = dir.join(name);
+                let (Ok(path), Ok(dir)) = (path.canonicalize(), dir.canonicalize()) else {
+                    continue;
+                };
+                if !path.starts_with(&dir) {
+                    continue;
+                }
+                if let Ok(source) = fs::read_to_string(&path) {
+                    return Some((path, source));
+                }
+            }
+        }
+        stdlib::module(name).map(|source| (PathBuf::from(name), source.to_owned()))
+    }
+
+    fn macro_index(&self, token: &Token) -> Option<usize> {
+        if token.typ != TokenType::Ident {
+            return None;
+        }
+        self.macros.iter().position(|m| m.name == token.lexeme)
     }
 
     fn emit_jump(&mut self, op: OpCode) -> usize {
@@ -177,65 +1036,188 @@ impl Compiler {
         let jump = self.program.len() - offset;
 
         let opcode = match self.program[offset] {
-            OpCode::Jump(_, kind) => OpCode::Jump(jump, kind),
+            OpCode::Jump(_, kind, cell) => OpCode::Jump(jump, kind, cell),
+            OpCode::SkipProc(_) => OpCode::SkipProc(jump),
+            OpCode::BranchIfZero(_, cell) => OpCode::BranchIfZero(jump, cell),
+            OpCode::Branch(_) => OpCode::Branch(jump),
             _ => panic!("Jump was tried to be patched, opcode was not a jump!"),
         };
 
         self.program[offset] = opcode;
     }
 
-    fn plus_stmt(&mut self) {
-        self.parser.consume(TokenType::Number);
+    fn plus_stmt(&mut self) -> Result<(), CompileError> {
+        self.parser.consume(TokenType::Number)?;
         let num_token = self.parser.previous.clone();
         let num = match num_token.lexeme.parse::<usize>() {
             Ok(num) => num,
-            Err(_) => self.parser.error_at_current("Could not parse number"),
+            Err(_) => return self.parser.error_at(&num_token, "Could not parse number"),
         };
+        if num > MAX_REPEAT {
+            return self.parser.error_at(
+                &num_token,
+                &format!("Cannot repeat more than {} times", MAX_REPEAT),
+            );
+        }
 
+        // the last opcode of a block is only part of it, so a whole block
+        // is rejected before looking at that opcode
+        if self.block_end == Some(self.program.len()) {
+            return self.parser.error_at(
+                &num_token,
+                "Cannot repeat a loop, an if, a proc or an `expect_output`",
+            );
+        }
         let last_op = *self
             .program
             .last()
             .expect("Cannot repeat invalid statement");
+        let unrepeatable = match last_op {
+            OpCode::SkipProc(_) => Some("Cannot repeat a proc definition"),
+            OpCode::CaptureOutput => Some("Cannot repeat the start of an `expect_output`"),
+            // each jump belongs to a single block
+            OpCode::Jump(..) | OpCode::BranchIfZero(..) | OpCode::Branch(_) => {
+                Some("Cannot repeat the start of a loop, an if or an else")
+            }
+            _ => None,
+        };
+        if let Some(err) = unrepeatable {
+            return self.parser.error_at(&num_token, err);
+        }
 
         for _ in 0..num {
+            // a repeated call finds its proc along with the one it repeats
+            if let OpCode::Call(_) = last_op {
+                let (_, name) = self.calls[self.calls.len() - 1].clone();
+                self.calls.push((self.program.len(), name));
+            }
             self.emit(last_op);
         }
+        Ok(())
     }
 
     fn debug_stmt(&mut self) {
         self.emit(OpCode::Debug);
     }
 
-    fn statement(&mut self) {
-        if self.parser.matches(TokenType::Incr) {
-            self.incr_stmt();
-        } else if self.parser.matches(TokenType::Decr) {
-            self.decr_stmt();
-        } else if self.parser.matches(TokenType::Putch) {
-            self.putch_stmt();
-        } else if self.parser.matches(TokenType::Getch) {
-            self.getch_stmt();
-        } else if self.parser.matches(TokenType::Loop) {
-            self.loop_stmt();
-        } else if self.parser.matches(TokenType::Debug) {
+    fn statement(&mut self) -> Result<(), CompileError> {
+        let block = matches!(
+            self.parser.current.typ,
+            TokenType::Loop | TokenType::If | TokenType::Proc | TokenType::ExpectOutput
+        );
+        if self.parser.matches(TokenType::Make)? {
+            self.make_stmt()?;
+        } else if self.parser.matches(TokenType::Incr)? {
+            self.incr_stmt()?;
+        } else if self.parser.matches(TokenType::Decr)? {
+            self.decr_stmt()?;
+        } else if self.parser.matches(TokenType::Set)? {
+            self.set_stmt()?;
+        } else if self.parser.matches(TokenType::Putch)? {
+            self.putch_stmt()?;
+        } else if self.parser.matches(TokenType::Print)? {
+            self.print_stmt()?;
+        } else if self.parser.matches(TokenType::Getch)? {
+            self.getch_stmt()?;
+        } else if self.parser.matches(TokenType::Putu)? {
+            self.putu_stmt()?;
+        } else if self.parser.matches(TokenType::Putnum)? {
+            self.putnum_stmt()?;
+        } else if self.parser.matches(TokenType::Getnum)? {
+            self.getnum_stmt()?;
+        } else if self.parser.matches(TokenType::Loop)? {
+            self.loop_stmt()?;
+        } else if self.parser.matches(TokenType::Goto)? {
+            self.goto_stmt()?;
+        } else if self.parser.matches(TokenType::Alias)? {
+            self.alias_stmt()?;
+        } else if self.parser.matches(TokenType::If)? {
+            self.if_stmt()?;
+        } else if self.parser.matches(TokenType::Proc)? {
+            self.proc_stmt()?;
+        } else if self.parser.matches(TokenType::Call)? {
+            self.call_stmt()?;
+        } else if self.parser.matches(TokenType::Debug)? {
             self.debug_stmt();
-        } else if self.parser.matches(TokenType::Plus) {
-            self.plus_stmt();
+        } else if self.parser.matches(TokenType::Assert)? {
+            self.assert_stmt()?;
+        } else if self.parser.matches(TokenType::ExpectOutput)? {
+            self.expect_output_stmt()?;
+        } else if self.parser.matches(TokenType::Plus)? {
+            self.plus_stmt()?;
+        } else if self.parser.matches(TokenType::Macro)? {
+            self.macro_stmt()?;
+        } else if self.parser.matches(TokenType::Include)? {
+            self.include_stmt()?;
+        } else if let Some(index) = self.macro_index(&self.parser.current) {
+            self.parser.advance()?;
+            self.macro_use(index)?;
+        } else if let Some(idx) = self.assigned_idx() {
+            self.parser.advance()?;
+            self.assign_stmt(idx)?;
         } else {
-            self.parser.advance();
+            self.parser.advance()?;
+        }
+        if block {
+            self.block_end = Some(self.program.len());
         }
+        Ok(())
     }
 
+    /// Compiles the program, printing its first error and exiting if it has one
     pub fn compile(&mut self) -> Program {
-        self.parser.advance();
-        // always handles `make`s before everything
-        self.make_tape_decl();
-        self.make_idx_decl();
+        self.try_compile().unwrap_or_else(|error| {
+            eprintln!("ERROR: {}", error);
+            process::exit(1);
+        })
+    }
+
+    /// Compiles the program, or returns its first error
+    pub fn try_compile(&mut self) -> Result<Program, CompileError> {
+        self.program()
+    }
 
-        while !self.parser.matches(TokenType::EOF) {
-            self.statement();
+    fn program(&mut self) -> Result<Program, CompileError> {
+        self.parser.advance()?;
+        // definitions come before the program's first tape, if it makes its own
+        loop {
+            if self.parser.matches(TokenType::Macro)? {
+                self.macro_stmt()?;
+            } else if self.parser.matches(TokenType::Include)? {
+                self.include_stmt()?;
+            } else {
+                break;
+            }
+        }
+        // the first tape is the default one unless the program starts by making its own
+        if !self.parser.check(TokenType::Make) {
+            self.default_make_tape();
         }
 
-        self.program.clone()
+        while !self.parser.matches(TokenType::EOF)? {
+            self.statement()?;
+        }
+        self.resolve_calls()?;
+
+        Ok(Program {
+            code: self.program.clone(),
+            lines: self.lines.clone(),
+            strings: self.strings.clone(),
+            files: self.files.clone(),
+            op_files: self.op_files.clone(),
+            procs: self.procs.clone(),
+        })
+    }
+}
+
+/// The character an escape like `\n` stands for, in char and string literals
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None,
     }
 }
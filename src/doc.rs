@@ -0,0 +1,24 @@
+use std::io::{self, Write};
+use tape::compiler::DocItem;
+
+/// Writes the Markdown documentation of a module: the doc comment at its top,
+/// then every definition with the doc comment right before it.
+pub fn write_doc(
+    title: &str,
+    module_doc: Option<&str>,
+    items: &[DocItem],
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    writeln!(out, "# {}", title)?;
+    if let Some(doc) = module_doc {
+        writeln!(out, "\n{}", doc)?;
+    }
+
+    for item in items {
+        writeln!(out, "\n## `{}`", item.signature)?;
+        if let Some(doc) = &item.doc {
+            writeln!(out, "\n{}", doc)?;
+        }
+    }
+    Ok(())
+}
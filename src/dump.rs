@@ -0,0 +1,116 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use tape::jit_compiler::JitCode;
+use tape::stdlib;
+use tape::vm::{BracketKind, OpCode, Program};
+
+/// Where a jump opcode at `index` lands, using the same arithmetic as `Vm::run`
+fn jump_target(index: usize, op: &OpCode) -> Option<usize> {
+    match op {
+        OpCode::Jump(offset, BracketKind::Close, _) => Some(index + offset),
+        OpCode::Jump(offset, BracketKind::Open, _) => Some(index - offset + 1),
+        OpCode::BranchIfZero(offset, _) | OpCode::Branch(offset) | OpCode::SkipProc(offset) => {
+            Some(index + offset)
+        }
+        OpCode::Call(target) => Some(*target),
+        _ => None,
+    }
+}
+
+fn describe(program: &Program, index: usize) -> String {
+    let op = &program.code[index];
+    if let OpCode::Print(id) | OpCode::ExpectOutput(id) = op {
+        let string = String::from_utf8_lossy(&program.strings[*id]);
+        return format!("{:?} {:?}", op, string);
+    }
+    match jump_target(index, op) {
+        Some(target) => format!("{:?} -> {:04}", op, target),
+        None => format!("{:?}", op),
+    }
+}
+
+pub fn source_line(source: &str, line: u32) -> &str {
+    source
+        .lines()
+        .nth((line as usize).saturating_sub(1))
+        .unwrap_or("")
+        .trim()
+}
+
+/// The source of an included file, where relative paths are standard library modules
+pub fn included_source(path: &Path) -> String {
+    if path.is_relative() {
+        let module = path.to_str().and_then(stdlib::module);
+        return module.unwrap_or_default().to_owned();
+    }
+    fs::read_to_string(path).unwrap_or_default()
+}
+
+/// Prints the opcode listing, indented by loop nesting, with the source line
+/// each run of opcodes was compiled from.
+pub fn dump_ir(program: &Program, source: &str, out: &mut dyn Write) -> io::Result<()> {
+    let included: Vec<String> = program.files.iter().map(|p| included_source(p)).collect();
+    let mut depth = 0;
+    let mut last_line = None;
+
+    for (i, op) in program.code.iter().enumerate() {
+        let line = (program.op_files[i], program.lines[i]);
+        if last_line != Some(line) {
+            match line {
+                (Some(file), line) => writeln!(
+                    out,
+                    "; line {} of {}: {}",
+                    line,
+                    program.files[file].display(),
+                    source_line(&included[file], line)
+                )?,
+                (None, line) => writeln!(out, "; line {}: {}", line, source_line(source, line))?,
+            }
+            last_line = Some(line);
+        }
+
+        if let OpCode::Jump(_, BracketKind::Open, _) = op {
+            depth -= 1;
+        }
+        writeln!(
+            out,
+            "{:04}  {}{}",
+            i,
+            "  ".repeat(depth),
+            describe(program, i)
+        )?;
+        if let OpCode::Jump(_, BracketKind::Close, _) = op {
+            depth += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the JIT's machine code, each opcode's instructions preceded by the opcode itself
+pub fn dump_asm(program: &Program, jit_code: &JitCode, out: &mut dyn Write) -> io::Result<()> {
+    let op_offsets = jit_code.op_offsets();
+    let mut next_op = 0;
+    let mut in_epilogue = false;
+
+    writeln!(out, "; prologue")?;
+    for (offset, instruction) in jit_code.disassemble() {
+        while next_op < op_offsets.len() && op_offsets[next_op] <= offset {
+            let line = program.lines[next_op];
+            writeln!(
+                out,
+                "; {:04} line {}: {}",
+                next_op,
+                line,
+                describe(program, next_op)
+            )?;
+            next_op += 1;
+        }
+        if !in_epilogue && next_op == op_offsets.len() && offset >= jit_code.epilogue_offset() {
+            writeln!(out, "; epilogue")?;
+            in_epilogue = true;
+        }
+        writeln!(out, "  {:06x}  {}", offset, instruction)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use super::JitCode;
+use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+
+impl JitCode {
+    /// Offset in the compiled code where each opcode's machine code begins
+    pub fn op_offsets(&self) -> &[usize] {
+        &self.op_offsets
+    }
+
+    /// Offset in the compiled code where the epilogue begins
+    pub fn epilogue_offset(&self) -> usize {
+        self.end
+    }
+
+    /// Decodes the compiled code into `(offset, instruction)` pairs.
+    /// Branch targets are printed as offsets into the code, too.
+    pub fn disassemble(&self) -> Vec<(usize, String)> {
+        let mut decoder = Decoder::with_ip(64, &self.code, 0, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        formatter.options_mut().set_branch_leading_zeros(false);
+        let mut instruction = Instruction::default();
+        let mut listing = vec![];
+
+        while decoder.can_decode() {
+            decoder.decode_out(&mut instruction);
+            let mut text = String::new();
+            formatter.format(&instruction, &mut text);
+            listing.push((instruction.ip() as usize, text));
+        }
+        listing
+    }
+}
@@ -0,0 +1,303 @@
+//! Minimal implementation of the GDB JIT compilation interface
+//! (https://sourceware.org/gdb/current/onlinedocs/gdb.html/JIT-Interface.html).
+//!
+//! For every registered program we build a tiny in-memory ELF object holding
+//! only a symbol table, whose `.text` section is placed at the address of the
+//! JIT-compiled code, and link it into `__jit_debug_descriptor`.
+
+use super::Symbol;
+use std::ptr;
+use std::sync::Mutex;
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+// GDB sets a breakpoint on this function and reads the descriptor whenever it is called
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+// the descriptor is process-global, so every update must be serialized
+static DESCRIPTOR_LOCK: Mutex<()> = Mutex::new(());
+
+/// Keeps a program's symbols registered with GDB until dropped
+pub struct GdbRegistration {
+    entry: Box<JitCodeEntry>,
+    _symfile: Vec<u8>,
+}
+
+impl GdbRegistration {
+    pub fn new(symbols: &[Symbol], file_name: &str) -> Self {
+        let symfile = build_elf(symbols, file_name);
+        let mut entry = Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        });
+
+        let _guard = DESCRIPTOR_LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry_ptr: *mut JitCodeEntry = &mut *entry;
+            entry.next_entry = (*descriptor).first_entry;
+            if !entry.next_entry.is_null() {
+                (*entry.next_entry).prev_entry = entry_ptr;
+            }
+            (*descriptor).first_entry = entry_ptr;
+            (*descriptor).relevant_entry = entry_ptr;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        }
+
+        GdbRegistration {
+            entry,
+            _symfile: symfile,
+        }
+    }
+}
+
+impl Drop for GdbRegistration {
+    fn drop(&mut self) {
+        let _guard = DESCRIPTOR_LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry_ptr: *mut JitCodeEntry = &mut *self.entry;
+            if self.entry.prev_entry.is_null() {
+                (*descriptor).first_entry = self.entry.next_entry;
+            } else {
+                (*self.entry.prev_entry).next_entry = self.entry.next_entry;
+            }
+            if !self.entry.next_entry.is_null() {
+                (*self.entry.next_entry).prev_entry = self.entry.prev_entry;
+            }
+            (*descriptor).relevant_entry = entry_ptr;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+        }
+    }
+}
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHN_ABS: u16 = 0xfff1;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_FILE: u8 = 4;
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+struct StrTab(Vec<u8>);
+
+impl StrTab {
+    fn new() -> Self {
+        StrTab(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+/// Builds a relocatable x86-64 ELF object with a `.text` section placed over
+/// the JIT-compiled code and one `STT_FUNC` symbol per region.
+fn build_elf(symbols: &[Symbol], file_name: &str) -> Vec<u8> {
+    let text_addr = symbols.iter().map(|s| s.addr).min().unwrap_or(0);
+    let text_end = symbols.iter().map(|s| s.addr + s.size).max().unwrap_or(0);
+
+    let mut shstrtab = StrTab::new();
+    let text_name = shstrtab.add(".text");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let symtab_name = shstrtab.add(".symtab");
+
+    let mut strtab = StrTab::new();
+    let mut symtab = vec![0u8; SYM_SIZE];
+    push_sym(
+        &mut symtab,
+        strtab.add(file_name),
+        STB_LOCAL,
+        STT_FILE,
+        SHN_ABS,
+        0,
+        0,
+    );
+    for symbol in symbols {
+        let value = (symbol.addr - text_addr) as u64;
+        let name = strtab.add(&symbol.name);
+        push_sym(
+            &mut symtab,
+            name,
+            STB_GLOBAL,
+            STT_FUNC,
+            1,
+            value,
+            symbol.size as u64,
+        );
+    }
+
+    let shstrtab_offset = EHDR_SIZE;
+    let strtab_offset = shstrtab_offset + shstrtab.0.len();
+    let symtab_offset = align8(strtab_offset + strtab.0.len());
+    let shdrs_offset = symtab_offset + symtab.len();
+
+    let mut elf = Vec::with_capacity(shdrs_offset + 5 * SHDR_SIZE);
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&1u16.to_le_bytes()); // e_type: ET_REL
+    elf.extend_from_slice(&62u16.to_le_bytes()); // e_machine: EM_X86_64
+    elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&(shdrs_offset as u64).to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    elf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&0u16.to_le_bytes()); // e_phentsize
+    elf.extend_from_slice(&0u16.to_le_bytes()); // e_phnum
+    elf.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&5u16.to_le_bytes()); // e_shnum
+    elf.extend_from_slice(&2u16.to_le_bytes()); // e_shstrndx
+
+    elf.extend_from_slice(&shstrtab.0);
+    elf.extend_from_slice(&strtab.0);
+    elf.resize(symtab_offset, 0);
+    elf.extend_from_slice(&symtab);
+
+    let text_size = (text_end - text_addr) as u64;
+    let symtab_size = symtab.len() as u64;
+    let text_flags = SHF_ALLOC | SHF_EXECINSTR;
+    push_shdr(&mut elf, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+    push_shdr(
+        &mut elf,
+        text_name,
+        SHT_NOBITS,
+        text_flags,
+        text_addr as u64,
+        0,
+        text_size,
+        0,
+        0,
+        16,
+    );
+    let len = shstrtab.0.len() as u64;
+    push_shdr(
+        &mut elf,
+        shstrtab_name,
+        SHT_STRTAB,
+        0,
+        0,
+        shstrtab_offset as u64,
+        len,
+        0,
+        0,
+        1,
+    );
+    let len = strtab.0.len() as u64;
+    push_shdr(
+        &mut elf,
+        strtab_name,
+        SHT_STRTAB,
+        0,
+        0,
+        strtab_offset as u64,
+        len,
+        0,
+        0,
+        1,
+    );
+    // sh_link points at .strtab, sh_info is the index of the first global symbol
+    push_shdr(
+        &mut elf,
+        symtab_name,
+        SHT_SYMTAB,
+        0,
+        0,
+        symtab_offset as u64,
+        symtab_size,
+        3,
+        2,
+        8,
+    );
+
+    elf
+}
+
+fn align8(n: usize) -> usize {
+    (n + 7) & !7
+}
+
+fn push_sym(out: &mut Vec<u8>, name: u32, bind: u8, typ: u8, shndx: u16, value: u64, size: u64) {
+    out.extend_from_slice(&name.to_le_bytes());
+    out.push((bind << 4) | typ);
+    out.push(0); // st_other
+    out.extend_from_slice(&shndx.to_le_bytes());
+    out.extend_from_slice(&value.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+}
+
+#[allow(clippy::too_many_arguments)]
+fn push_shdr(
+    out: &mut Vec<u8>,
+    name: u32,
+    typ: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+) {
+    out.extend_from_slice(&name.to_le_bytes());
+    out.extend_from_slice(&typ.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&link.to_le_bytes());
+    out.extend_from_slice(&info.to_le_bytes());
+    out.extend_from_slice(&align.to_le_bytes());
+    let entsize: u64 = if typ == SHT_SYMTAB {
+        SYM_SIZE as u64
+    } else {
+        0
+    };
+    out.extend_from_slice(&entsize.to_le_bytes());
+}
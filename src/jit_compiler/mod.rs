@@ -1,36 +1,90 @@
-use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
-use std::io::{BufRead, Read, Write};
+mod disasm;
+#[cfg(feature = "gdb-jit")]
+mod gdb_jit;
+mod perf_map;
+mod symbols;
+
+use dynasmrt::{dynasm, DynamicLabel, DynasmApi, DynasmLabelApi};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, ErrorKind, Read, Write};
 use std::mem;
 use std::slice;
-use std::u8;
+use std::time::Instant;
+
+use crate::limits::{self, Limits};
+use crate::tape_struct::Tape;
+use crate::vm::{self, BracketKind, Cell, Eof, IdxId, NumFormat, OpCode, Program};
+
+#[cfg(feature = "gdb-jit")]
+pub use gdb_jit::GdbRegistration;
+pub use perf_map::write_perf_map;
+pub use symbols::Symbol;
 
-use crate::vm::{BracketKind, OpCode, Program};
+// how many loop iterations run between two wall-clock checks
+const CLOCK_CHECK_INTERVAL: u32 = 1 << 16;
 
-const TAPE_SIZE: usize = 30_000;
+// every index variable lives in its own register: rdx, then the callee-saved
+// rbx, rsi, rdi and r12-r15, which the prologue saves for the caller along
+// with rbp, the frame pointer
+const IDX_REGS: [u8; vm::MAX_IDXS] = [2, 3, 6, 7, 12, 13, 14, 15];
 
+// r11, holding the position of a cell not simply under an index
+const POSITION_REG: u8 = 11;
+
+// a_begin and a_len describe the first tape, the other tapes are reached
+// through `JitState::tape_table`
 macro_rules! my_dynasm {
     ($ops:ident $($t:tt)*) => {
         dynasm!($ops
             ; .arch x64
             ; .alias a_state, rcx
-            ; .alias a_current, rdx
             ; .alias a_begin, r8
-            ; .alias a_end, r9
+            ; .alias a_len, r9
+            ; .alias a_cell, r10
             ; .alias retval, rax
             $($t)*
         )
     }
 }
 
+// the home space of rcx, rdx, r8 and r9 the caller reserved above the
+// return address, relative to rbp once the prologue has set up its frame
+const HOME: i32 = 0x70;
+
+// procs run on the machine stack, each call reserving this much besides the
+// return address so the stack stays aligned and has shadow space for externs
+const PROC_FRAME: i32 = 0x28;
+
+// room on the stack the code runs on for the extern functions, besides the
+// frames of `max_call_depth` nested calls
+const EXTERN_STACK: usize = 1 << 20;
+
 macro_rules! prologue {
     ($ops:ident) => {{
         let start = $ops.offset();
         my_dynasm!($ops
+            ; push rbp
+            ; push rbx
+            ; push rsi
+            ; push rdi
+            ; push r12
+            ; push r13
+            ; push r14
+            ; push r15
             ; sub rsp, 0x28
-            ; mov [rsp + 0x30], rcx
-            ; mov [rsp + 0x40], r8
-            ; mov [rsp + 0x48], r9
+            ; mov rbp, rsp
+            ; mov [rbp + HOME], rcx
+            ; mov [rbp + HOME + 0x10], r8
+            ; mov [rbp + HOME + 0x18], r9
+            // the rest runs on the stack `run_jit` made for it, left by the
+            // epilogue restoring rsp from rbp
+            ; mov rsp, [a_state + mem::offset_of!(JitState, stack_top) as i32]
         );
+        for &reg in &IDX_REGS[1..] {
+            my_dynasm!($ops
+                ; xor Rd(reg), Rd(reg)
+            );
+        }
         start
     }};
 }
@@ -38,116 +92,518 @@ macro_rules! prologue {
 macro_rules! epilogue {
     ($ops:ident, $e:expr) => {my_dynasm!($ops
         ; mov retval, $e
+        ; mov rsp, rbp
         ; add rsp, 0x28
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rdi
+        ; pop rsi
+        ; pop rbx
+        ; pop rbp
         ; ret
     );};
 }
 
+/// Charges `amount` executed opcodes against the fuel in `JitState`.
+/// Straight-line code is charged as a whole right before the jump ending it,
+/// or before any input or output, so the total and the output match what
+/// `Vm::run` would have done.
+macro_rules! charge_fuel {
+    ($ops:ident, $amount:expr) => {{
+        let amount = i32::try_from($amount).map_err(|_| "program too large")?;
+        if amount > 0 {
+            my_dynasm!($ops
+                ; sub QWORD [a_state + mem::offset_of!(JitState, fuel) as i32], amount
+                ; jb ->out_of_fuel
+            );
+        }
+    }};
+}
+
+/// Loads the address `cell` is offset from into a_cell and evaluates to the
+/// offset, failing if the cell is outside of its tape
+macro_rules! cell_address {
+    ($ops:ident, $failures:ident, $pending:expr, $cell:expr) => {{
+        let cell: Cell = $cell;
+        let out_of_range = $failures.label(&mut $ops, Failure::OutOfRange, $pending);
+        let offset = i32::try_from(cell.offset).map_err(|_| "cell position too large")?;
+        // the cell's position, for the bounds check
+        let (base, position) = match cell.idx {
+            Some(idx) if offset == 0 => (Some(idx_reg(idx)?), idx_reg(idx)?),
+            Some(idx) => {
+                let index = idx_reg(idx)?;
+                my_dynasm!($ops
+                    ; lea Rq(POSITION_REG), [Rq(index) + offset]
+                );
+                (Some(index), POSITION_REG)
+            }
+            None => {
+                my_dynasm!($ops
+                    ; mov Rq(POSITION_REG), offset
+                );
+                (None, POSITION_REG)
+            }
+        };
+        if cell.tape == 0 {
+            my_dynasm!($ops
+                ; cmp Rq(position), a_len
+                ; jae =>out_of_range
+            );
+            match base {
+                Some(index) => my_dynasm!($ops
+                    ; lea a_cell, [a_begin + Rq(index)]
+                ),
+                None => my_dynasm!($ops
+                    ; mov a_cell, a_begin
+                ),
+            }
+        } else {
+            let entry = (cell.tape * mem::size_of::<TapeEntry>()) as i32;
+            my_dynasm!($ops
+                ; mov rax, [a_state + mem::offset_of!(JitState, tape_table) as i32]
+                ; cmp Rq(position), [rax + entry + mem::offset_of!(TapeEntry, len) as i32]
+                ; jae =>out_of_range
+                ; mov a_cell, [rax + entry + mem::offset_of!(TapeEntry, ptr) as i32]
+            );
+            if let Some(index) = base {
+                my_dynasm!($ops
+                    ; add a_cell, Rq(index)
+                );
+            }
+        }
+        offset
+    }};
+}
+
+/// Loads the address of `cell` itself into a_cell, for the functions called with it
+macro_rules! cell_pointer {
+    ($ops:ident, $failures:ident, $pending:expr, $cell:expr) => {{
+        let offset = cell_address!($ops, $failures, $pending, $cell);
+        if offset != 0 {
+            my_dynasm!($ops
+                ; lea a_cell, [a_cell + offset]
+            );
+        }
+    }};
+}
+
+/// Calls `JitState::$addr(state, a_cell)`
 macro_rules! call_extern {
     ($ops:ident, $addr:expr) => {my_dynasm!($ops
-        ; mov [rsp + 0x38], rdx
-        ; mov rax, QWORD $addr as _
+        ; mov [rbp + HOME + 0x08], rdx
+        ; mov rdx, a_cell
+        ; mov rax, QWORD $addr as *const () as _
         ; call rax
-        ; mov rcx, [rsp + 0x30]
-        ; mov rdx, [rsp + 0x38]
-        ; mov r8,  [rsp + 0x40]
-        ; mov r9,  [rsp + 0x48]
+        ; mov rcx, [rbp + HOME]
+        ; mov rdx, [rbp + HOME + 0x08]
+        ; mov r8,  [rbp + HOME + 0x10]
+        ; mov r9,  [rbp + HOME + 0x18]
+    );};
+}
+
+/// Counts down to the next wall-clock check, on loop back-edges and calls
+macro_rules! tick_clock {
+    ($ops:ident) => {my_dynasm!($ops
+        ; sub DWORD [a_state + mem::offset_of!(JitState, ticks) as i32], 1
+        ; jnz >ticked
+        ;; call_extern!($ops, JitState::check_clock)
+        ; cmp al, 0
+        ; jnz ->extern_failure
+        ;ticked:
     );};
 }
 
+/// The register holding index variable `idx`
+fn idx_reg(idx: IdxId) -> Result<u8, &'static str> {
+    IDX_REGS
+        .get(idx)
+        .copied()
+        .ok_or("too many idx variables for the JIT")
+}
+
+/// A check an opcode runs before the fuel it takes is charged
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Failure {
+    Overflow,
+    OutOfRange,
+    TapeTooLarge,
+    Assertion,
+}
+
+/// Where failed checks jump to, by what failed and how many opcodes have run
+/// since fuel was last charged, the failing one included. The VM would run
+/// out of fuel before failing if less is left than that, so these charge for
+/// them on the way out.
+#[derive(Default)]
+struct Failures(BTreeMap<(Failure, usize), DynamicLabel>);
+
+impl Failures {
+    fn label(
+        &mut self,
+        ops: &mut dynasmrt::x64::Assembler,
+        failure: Failure,
+        pending: usize,
+    ) -> DynamicLabel {
+        *self
+            .0
+            .entry((failure, pending))
+            .or_insert_with(|| ops.new_dynamic_label())
+    }
+}
+
+#[repr(C)]
+struct TapeEntry {
+    ptr: *mut u8,
+    len: usize,
+}
+
 pub struct JitState<'a> {
     pub input: Box<dyn BufRead + 'a>,
     pub output: Box<dyn Write + 'a>,
-    pub tape: [u8; TAPE_SIZE],
+    /// Every tape the program makes, indexed by `TapeId`
+    pub tapes: Vec<Vec<u8>>,
+    pub limits: Limits,
+    /// What `getch` and `getnum` do at the end of input
+    pub eof: Eof,
+    /// Where each tape is, with a length of 0 until it is made
+    tape_table: *mut TapeEntry,
+    /// Which tapes have been made, indexed by `TapeId`
+    made: Vec<bool>,
+    max_tape: u64,
+    fuel: u64,
+    /// How many more calls may nest
+    calls_left: u64,
+    /// Where the stack the code runs on starts, deep enough for every call to nest
+    stack_top: *mut u8,
+    ticks: u32,
+    deadline: Option<Instant>,
+    output_len: usize,
+    /// What each `expect_output` block being run has written so far, like `Vm`
+    captures: Vec<Vec<u8>>,
+    /// Set by the extern functions before they report a failure
+    error: Option<&'static str>,
 }
 
 pub struct JitCode {
     code: dynasmrt::ExecutableBuffer,
     start: dynasmrt::AssemblyOffset,
+    /// Offset in `code` where the machine code of each opcode begins
+    op_offsets: Vec<usize>,
+    /// Offset in `code` where the epilogue begins
+    end: usize,
+    /// Size of every tape the program makes, indexed by `TapeId`
+    tape_sizes: Vec<usize>,
+    /// The program's strings, only kept alive for the code pointing into them
+    #[allow(dead_code)]
+    strings: Vec<Vec<u8>>,
 }
 
 pub fn jit_compile(program: &Program) -> Result<JitCode, &'static str> {
     let mut ops = dynasmrt::x64::Assembler::new().unwrap();
     let mut loops = vec![];
+    let mut op_offsets = Vec::with_capacity(program.code.len());
+    let mut tape_sizes = vec![];
+    // moving the outer vector into `JitCode` leaves each string where it is
+    let strings = program.strings.clone();
+    // opcodes run since the last time fuel was charged
+    let mut pending = 0;
+    let mut failures = Failures::default();
+    // where proc bodies start, for `call`, and end, for skipping over them
+    let mut proc_starts: HashMap<usize, DynamicLabel> = HashMap::new();
+    let mut proc_ends: HashMap<usize, DynamicLabel> = HashMap::new();
+    // where the jumps of `if`s and `else`s land
+    let mut branch_targets: HashMap<usize, DynamicLabel> = HashMap::new();
+    for (i, op) in program.code.iter().enumerate() {
+        match *op {
+            OpCode::SkipProc(offset) => {
+                proc_starts.insert(i + 1, ops.new_dynamic_label());
+                proc_ends.insert(i + offset, ops.new_dynamic_label());
+            }
+            OpCode::BranchIfZero(offset, _) | OpCode::Branch(offset) => {
+                if offset == 0 || i + offset > program.code.len() {
+                    return Err("branch outside of the program");
+                }
+                branch_targets
+                    .entry(i + offset)
+                    .or_insert_with(|| ops.new_dynamic_label());
+            }
+            _ => {}
+        }
+    }
+    // index of the opcode following the proc being compiled
+    let mut proc_end = None;
     let start = prologue!(ops);
 
-    for (_i, op) in program.iter().enumerate() {
-        match op {
-            OpCode::MakeTape(_) => {}
-            OpCode::IncrPtr => {
-                let amount = 1; // TODO: make the amount accumulate to improve performance
+    for (i, op) in program.code.iter().enumerate() {
+        if let Some(&label) = branch_targets.get(&i) {
+            // code falling through to a branch target pays for itself, so
+            // both ways in arrive with nothing pending
+            charge_fuel!(ops, mem::take(&mut pending));
+            my_dynasm!(ops
+                ;=>label
+            );
+        }
+        op_offsets.push(ops.offset().0);
+        if let Some(&label) = proc_ends.get(&i) {
+            my_dynasm!(ops
+                ;=>label
+            );
+        }
+        if proc_end == Some(i) {
+            proc_end = None;
+        }
+        pending += 1;
+        match *op {
+            // tapes are allocated by `run_jit` before the code runs, as each is
+            // made exactly once, but stay out of reach until then
+            OpCode::MakeTape(id, size) => {
+                if tape_sizes.len() <= id {
+                    tape_sizes.resize(id + 1, 0);
+                }
+                tape_sizes[id] = size;
+                let too_large = failures.label(&mut ops, Failure::TapeTooLarge, pending);
+                my_dynasm!(ops
+                    ; mov rax, QWORD size as _
+                    ; cmp [a_state + mem::offset_of!(JitState, max_tape) as i32], rax
+                    ; jb =>too_large
+                    ; mov a_cell, QWORD id as _
+                    ;; call_extern!(ops, JitState::make_tape)
+                );
+                if id == 0 {
+                    my_dynasm!(ops
+                        ; mov rax, [a_state + mem::offset_of!(JitState, tape_table) as i32]
+                        ; mov a_begin, [rax + mem::offset_of!(TapeEntry, ptr) as i32]
+                        ; mov a_len, [rax + mem::offset_of!(TapeEntry, len) as i32]
+                        ; mov [rbp + HOME + 0x10], a_begin
+                        ; mov [rbp + HOME + 0x18], a_len
+                    );
+                }
+            }
+            OpCode::IncrPtr(idx) => {
+                let index = idx_reg(idx)?;
+                my_dynasm!(ops
+                    ; add Rq(index), 1
+                );
+            }
+            OpCode::SetPtr(idx, position) => {
+                let index = idx_reg(idx)?;
                 my_dynasm!(ops
-                    ; add a_current, (amount % TAPE_SIZE) as _
-                    ; cmp a_current, a_end
-                    ; jb >wrap
-                    ; sub a_current, TAPE_SIZE as _
-                    ;wrap:
+                    ; mov Rq(index), QWORD position as i64
                 );
             }
-            OpCode::DecrPtr => {
-                let amount = 1; // TODO: make the amount accumulate to improve performance
+            OpCode::DecrPtr(idx) => {
+                // stops at the first cell, like the VM
+                let index = idx_reg(idx)?;
                 my_dynasm!(ops
-                    ; sub a_current, (amount % TAPE_SIZE) as _
-                    ; cmp a_current, a_begin
-                    ; jae >wrap
-                    ; add a_current, TAPE_SIZE as _
-                    ;wrap:
+                    ; sub Rq(index), 1
+                    ; adc Rq(index), 0
                 );
             }
-            OpCode::IncrCell => {
-                let amount = 1; // TODO: make the amount accumulate to improve performance
-                if amount > u8::MAX as usize {
-                    return Err("An overflow occurred");
+            OpCode::MovePtr(idx, n) => {
+                let index = idx_reg(idx)?;
+                let amount = i32::try_from(n.unsigned_abs()).map_err(|_| "index move too large")?;
+                if n >= 0 {
+                    my_dynasm!(ops
+                        ; add Rq(index), amount
+                    );
+                } else {
+                    my_dynasm!(ops
+                        ; sub Rq(index), amount
+                        ; jnc >moved
+                        ; xor Rd(index), Rd(index)
+                        ;moved:
+                    );
                 }
+            }
+            OpCode::IncrCell(cell, n) => {
+                let offset = cell_address!(ops, failures, pending, cell);
+                let overflow = failures.label(&mut ops, Failure::Overflow, pending);
+                // an overflowing cell keeps its value, like in the VM
+                my_dynasm!(ops
+                    ; add BYTE [a_cell + offset], n as i8
+                    ; jnc >done
+                    ; sub BYTE [a_cell + offset], n as i8
+                    ; jmp =>overflow
+                    ;done:
+                );
+            }
+            OpCode::DecrCell(cell, n) => {
+                let offset = cell_address!(ops, failures, pending, cell);
+                let overflow = failures.label(&mut ops, Failure::Overflow, pending);
                 my_dynasm!(ops
-                    ; add BYTE [a_current], amount as _
-                    ; jo ->overflow
+                    ; sub BYTE [a_cell + offset], n as i8
+                    ; jnc >done
+                    ; add BYTE [a_cell + offset], n as i8
+                    ; jmp =>overflow
+                    ;done:
                 );
             }
-            OpCode::DecrCell => {
-                let amount = 1; // TODO: make the amount accumulate to improve performance
-                if amount > u8::MAX as usize {
-                    return Err("An overflow occurred");
+            OpCode::SetCell(cell, n) => {
+                let offset = cell_address!(ops, failures, pending, cell);
+                my_dynasm!(ops
+                    ; mov BYTE [a_cell + offset], n as i8
+                );
+            }
+            OpCode::AddProduct(cell, factor, n) | OpCode::SubProduct(cell, factor, n) => {
+                // the product waits on the stack while the cell's address is
+                // loaded, and is left there if that fails, for the epilogue
+                let offset = cell_address!(ops, failures, pending, factor);
+                my_dynasm!(ops
+                    ; movzx eax, BYTE [a_cell + offset]
+                    ; imul eax, eax, n as i32
+                    ; push rax
+                );
+                let offset = cell_address!(ops, failures, pending, cell);
+                let overflow = failures.label(&mut ops, Failure::Overflow, pending);
+                my_dynasm!(ops
+                    ; pop Rq(POSITION_REG)
+                    ; movzx eax, BYTE [a_cell + offset]
+                );
+                if let OpCode::AddProduct(..) = op {
+                    my_dynasm!(ops
+                        ; add eax, Rd(POSITION_REG)
+                        ; cmp eax, 0xff
+                        ; ja =>overflow
+                    );
+                } else {
+                    my_dynasm!(ops
+                        ; sub eax, Rd(POSITION_REG)
+                        ; jb =>overflow
+                    );
                 }
                 my_dynasm!(ops
-                    ; sub BYTE [a_current], amount as _
-                    ; jo ->overflow
+                    ; mov BYTE [a_cell + offset], al
                 );
             }
-            OpCode::PrintChar => {
+            OpCode::PrintChar(cell) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                cell_pointer!(ops, failures, pending, cell);
                 my_dynasm!(ops
                     ;; call_extern!(ops, JitState::putchar)
                     ; cmp al, 0
-                    ; jnz ->io_failure
+                    ; jnz ->extern_failure
                 );
             }
-            OpCode::GetChar => {
+            OpCode::GetChar(cell) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                cell_pointer!(ops, failures, pending, cell);
                 my_dynasm!(ops
                     ;; call_extern!(ops, JitState::getchar)
                     ; cmp al, 0
-                    ; jnz ->io_failure
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::PrintUtf8(cell) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                cell_pointer!(ops, failures, pending, cell);
+                my_dynasm!(ops
+                    ;; call_extern!(ops, JitState::putu)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::PrintWideUtf8(cell) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                // the last of the three cells is checked first, then the first
+                // one, whose address `putu_wide` gets
+                let last = cell.offset_by(2).ok_or("cell position too large")?;
+                cell_address!(ops, failures, pending, last);
+                cell_pointer!(ops, failures, pending, cell);
+                my_dynasm!(ops
+                    ;; call_extern!(ops, JitState::putu_wide)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::PrintNum(cell, format) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                cell_pointer!(ops, failures, pending, cell);
+                let putnum = match format {
+                    NumFormat::Decimal => JitState::putnum,
+                    NumFormat::Hex => JitState::puthex,
+                };
+                my_dynasm!(ops
+                    ;; call_extern!(ops, putnum)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::GetNum(cell) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                cell_pointer!(ops, failures, pending, cell);
+                my_dynasm!(ops
+                    ;; call_extern!(ops, JitState::getnum)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::Print(id) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                my_dynasm!(ops
+                    ; mov a_cell, QWORD &strings[id] as *const Vec<u8> as _
+                    ;; call_extern!(ops, JitState::print)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::Assert(cell, n) => {
+                let offset = cell_address!(ops, failures, pending, cell);
+                let failed = failures.label(&mut ops, Failure::Assertion, pending);
+                my_dynasm!(ops
+                    ; cmp BYTE [a_cell + offset], n as i8
+                    ; jne =>failed
+                );
+            }
+            OpCode::CaptureOutput => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                my_dynasm!(ops
+                    ;; call_extern!(ops, JitState::capture_output)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::ExpectOutput(id) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                my_dynasm!(ops
+                    ; mov a_cell, QWORD &strings[id] as *const Vec<u8> as _
+                    ;; call_extern!(ops, JitState::expect_output)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
+                );
+            }
+            OpCode::Debug => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                my_dynasm!(ops
+                    ;; call_extern!(ops, JitState::debug)
+                    ; cmp al, 0
+                    ; jnz ->extern_failure
                 );
             }
-            OpCode::Debug => todo!(),
 
-            #[allow(unused)]
-            OpCode::Jump(ref offset, kind) => match kind {
+            OpCode::Jump(_, kind, cell) => match kind {
                 BracketKind::Close => {
                     // TODO: handle the [-] specific case
 
                     let backward_label = ops.new_dynamic_label();
                     let forward_label = ops.new_dynamic_label();
                     loops.push((backward_label, forward_label));
+                    charge_fuel!(ops, mem::take(&mut pending));
+                    let offset = cell_address!(ops, failures, pending, cell);
                     my_dynasm!(ops
-                        ; cmp BYTE [a_current], 0
+                        ; cmp BYTE [a_cell + offset], 0
                         ; jz =>forward_label
                         ;=>backward_label
                     );
                 }
                 BracketKind::Open => {
                     if let Some((backward_label, forward_label)) = loops.pop() {
+                        charge_fuel!(ops, mem::take(&mut pending));
+                        tick_clock!(ops);
+                        let offset = cell_address!(ops, failures, pending, cell);
                         my_dynasm!(ops
-                            ; cmp BYTE [a_current], 0
+                            ; cmp BYTE [a_cell + offset], 0
                             ; jnz =>backward_label
                             ;=>forward_label
                         );
@@ -156,65 +612,405 @@ pub fn jit_compile(program: &Program) -> Result<JitCode, &'static str> {
                     }
                 }
             },
+            OpCode::SkipProc(offset) => {
+                if proc_end.is_some() || !loops.is_empty() {
+                    return Err("proc inside a loop or another proc");
+                }
+                proc_end = Some(i + offset);
+                charge_fuel!(ops, mem::take(&mut pending));
+                my_dynasm!(ops
+                    ; jmp =>proc_ends[&(i + offset)]
+                );
+            }
+            OpCode::BranchIfZero(offset, cell) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                let target = branch_targets[&(i + offset)];
+                let offset = cell_address!(ops, failures, pending, cell);
+                my_dynasm!(ops
+                    ; cmp BYTE [a_cell + offset], 0
+                    ; jz =>target
+                );
+            }
+            OpCode::Branch(offset) => {
+                charge_fuel!(ops, mem::take(&mut pending));
+                my_dynasm!(ops
+                    ; jmp =>branch_targets[&(i + offset)]
+                );
+            }
+            OpCode::Call(target) => {
+                let Some(&label) = proc_starts.get(&target) else {
+                    return Err("call to something that is not a proc");
+                };
+                charge_fuel!(ops, mem::take(&mut pending));
+                tick_clock!(ops);
+                my_dynasm!(ops
+                    ; sub QWORD [a_state + mem::offset_of!(JitState, calls_left) as i32], 1
+                    ; jb ->call_too_deep
+                    ; call =>label
+                );
+            }
+            OpCode::Return => {
+                if proc_end.is_none() {
+                    return Err("return outside of a proc");
+                }
+                charge_fuel!(ops, mem::take(&mut pending));
+                my_dynasm!(ops
+                    ; add QWORD [a_state + mem::offset_of!(JitState, calls_left) as i32], 1
+                    ; add rsp, PROC_FRAME
+                    ; ret
+                );
+            }
+        }
+
+        // a proc's frame is set up once the code jumping over its body is emitted
+        if let OpCode::SkipProc(_) = op {
+            my_dynasm!(ops
+                ;=>proc_starts[&(i + 1)]
+                ; sub rsp, PROC_FRAME
+            );
         }
     }
 
-    if loops.len() != 0 {
+    if !loops.is_empty() {
         return Err("[ without matching ]");
     }
+    if let Some(&label) = proc_ends.get(&program.code.len()) {
+        my_dynasm!(ops
+            ;=>label
+        );
+    }
+    charge_fuel!(ops, mem::take(&mut pending));
+    if let Some(&label) = branch_targets.get(&program.code.len()) {
+        my_dynasm!(ops
+            ;=>label
+        );
+    }
+    let end = ops.offset().0;
     my_dynasm!(ops
         ;; epilogue!(ops, 0)
+    );
+    for ((failure, pending), label) in failures.0 {
+        my_dynasm!(ops
+            ;=>label
+        );
+        charge_fuel!(ops, pending);
+        match failure {
+            Failure::Overflow => my_dynasm!(ops
+                ; jmp ->overflow
+            ),
+            Failure::OutOfRange => my_dynasm!(ops
+                ; jmp ->out_of_range
+            ),
+            Failure::TapeTooLarge => my_dynasm!(ops
+                ; jmp ->tape_too_large
+            ),
+            Failure::Assertion => my_dynasm!(ops
+                ; jmp ->assertion_failed
+            ),
+        }
+    }
+    my_dynasm!(ops
         ;->overflow:
         ;; epilogue!(ops, 1)
-        ;->io_failure:
+        ;->extern_failure:
         ;; epilogue!(ops, 2)
+        // the charge that ran out wrapped the fuel around, and all of it was used
+        ;->out_of_fuel:
+        ; mov QWORD [a_state + mem::offset_of!(JitState, fuel) as i32], 0
+        ;; epilogue!(ops, 3)
+        ;->out_of_range:
+        ;; epilogue!(ops, 4)
+        ;->tape_too_large:
+        ;; epilogue!(ops, 5)
+        ;->call_too_deep:
+        ;; epilogue!(ops, 6)
+        ;->assertion_failed:
+        ;; epilogue!(ops, 7)
     );
 
     let code = ops.finalize().unwrap();
-    Ok(JitCode { code, start })
+    Ok(JitCode {
+        code,
+        start,
+        op_offsets,
+        end,
+        tape_sizes,
+        strings,
+    })
 }
 
-pub fn run_jit(state: &mut JitState, jit_code: JitCode) -> Result<(), &'static str> {
-    let f: extern "win64" fn(*mut JitState, *mut u8, *mut u8, *const u8) -> u8 =
+/// Fails a run whose `max_call_depth` needs more stack than can be allocated
+pub const NO_ROOM_FOR_CALLS: &str = "Not enough memory for max_call_depth calls";
+
+pub fn run_jit(state: &mut JitState, jit_code: &JitCode) -> Result<(), &'static str> {
+    let f: extern "win64" fn(*mut JitState, usize, *mut u8, usize) -> u8 =
         unsafe { mem::transmute(jit_code.code.ptr(jit_code.start)) };
-    let start = state.tape.as_mut_ptr();
-    let end = unsafe { start.offset(TAPE_SIZE as isize) };
-    let res = f(state, start, start, end);
-    if res == 0 {
-        Ok(())
-    } else if res == 1 {
-        Err("An overflow occurred")
-    } else if res == 2 {
-        Err("IO error")
-    } else {
-        panic!("Unknown error code");
+
+    // a tape over the limit fails its `make` before anything uses it
+    let max_tape = state.limits.max_tape.unwrap_or(usize::MAX);
+    state.tapes = jit_code
+        .tape_sizes
+        .iter()
+        .map(|&size| {
+            if size > max_tape {
+                vec![]
+            } else {
+                vec![0; size]
+            }
+        })
+        .collect();
+    let mut tape_table: Vec<TapeEntry> = state
+        .tapes
+        .iter_mut()
+        .map(|tape| TapeEntry {
+            ptr: tape.as_mut_ptr(),
+            len: 0,
+        })
+        .collect();
+    state.tape_table = tape_table.as_mut_ptr();
+    state.made = vec![false; state.tapes.len()];
+    state.max_tape = state.limits.max_tape.map_or(u64::MAX, |max| max as u64);
+    state.fuel = state.limits.fuel.unwrap_or(u64::MAX);
+    state.calls_left = state.limits.max_call_depth as u64;
+    // the machine stack may be too small for that many calls, so the code gets
+    // its own, only touched as deep as the calls go
+    let mut stack: Vec<u8> = vec![];
+    let frames = state
+        .limits
+        .max_call_depth
+        .checked_mul(PROC_FRAME as usize + 8)
+        .and_then(|size| size.checked_add(EXTERN_STACK))
+        .ok_or(NO_ROOM_FOR_CALLS)?;
+    stack
+        .try_reserve_exact(frames)
+        .map_err(|_| NO_ROOM_FOR_CALLS)?;
+    // aligned like the frame the prologue made, with shadow space for externs
+    let top = (stack.as_mut_ptr() as usize + frames) & !0xf;
+    state.stack_top = (top - 0x30) as *mut u8;
+    state.ticks = CLOCK_CHECK_INTERVAL;
+    state.deadline = state.limits.timeout.map(|timeout| Instant::now() + timeout);
+    state.output_len = 0;
+    state.captures.clear();
+    state.error = None;
+
+    let (begin, len) = tape_table
+        .first()
+        .map_or((std::ptr::null_mut(), 0), |tape| (tape.ptr, tape.len));
+    let res = f(state, 0, begin, len);
+    state.tape_table = std::ptr::null_mut();
+    state.stack_top = std::ptr::null_mut();
+    match res {
+        0 => Ok(()),
+        1 => Err("An overflow occurred"),
+        2 => Err(state.error.take().unwrap_or("IO error")),
+        3 => Err(limits::FUEL_EXHAUSTED),
+        4 => Err("Index out of range"),
+        5 => Err(limits::TAPE_TOO_LARGE),
+        6 => Err(limits::CALL_TOO_DEEP),
+        7 => Err(vm::ASSERTION_FAILED),
+        _ => panic!("Unknown error code"),
     }
 }
 
 impl<'a> JitState<'a> {
     unsafe extern "win64" fn getchar(state: *mut JitState, cell: *mut u8) -> u8 {
         let state = &mut *state;
-        let err = state.output.flush().is_err();
-        (state
-            .input
-            .read_exact(slice::from_raw_parts_mut(cell, 1))
-            .is_err()
-            || err) as u8
+        if state.output.flush().is_err() {
+            state.error = Some("IO error");
+            return 1;
+        }
+        let result = match state.read_byte() {
+            Ok(Some(byte)) => {
+                *cell = byte;
+                Ok(())
+            }
+            Ok(None) => state.eof.apply(&mut *cell),
+            Err(err) => Err(err),
+        };
+        state.report(result)
     }
 
     unsafe extern "win64" fn putchar(state: *mut JitState, cell: *mut u8) -> u8 {
+        (*state).write_output(slice::from_raw_parts(cell, 1))
+    }
+
+    unsafe extern "win64" fn print(state: *mut JitState, string: *const Vec<u8>) -> u8 {
+        (*state).write_output(&*string)
+    }
+
+    unsafe extern "win64" fn putu(state: *mut JitState, cell: *mut u8) -> u8 {
+        let c = char::from(*cell);
+        (*state).write_output(c.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    unsafe extern "win64" fn putu_wide(state: *mut JitState, cell: *mut u8) -> u8 {
+        let code_point = u32::from_le_bytes([*cell, *cell.add(1), *cell.add(2), 0]);
+        let Some(c) = char::from_u32(code_point) else {
+            (*state).error = Some("Invalid code point");
+            return 1;
+        };
+        (*state).write_output(c.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    unsafe extern "win64" fn putnum(state: *mut JitState, cell: *mut u8) -> u8 {
+        (*state).write_output(NumFormat::Decimal.format(*cell).as_bytes())
+    }
+
+    unsafe extern "win64" fn puthex(state: *mut JitState, cell: *mut u8) -> u8 {
+        (*state).write_output(NumFormat::Hex.format(*cell).as_bytes())
+    }
+
+    unsafe extern "win64" fn getnum(state: *mut JitState, cell: *mut u8) -> u8 {
         let state = &mut *state;
-        state
-            .output
-            .write_all(slice::from_raw_parts(cell, 1))
-            .is_err() as u8
+        if state.output.flush().is_err() {
+            state.error = Some("IO error");
+            return 1;
+        }
+        let mut failed = Ok(());
+        let number = vm::read_number(|| {
+            state.read_byte().unwrap_or_else(|err| {
+                failed = Err(err);
+                None
+            })
+        });
+        let result = match failed.and(number) {
+            Ok(Some(value)) => {
+                *cell = value;
+                Ok(())
+            }
+            Ok(None) => state.eof.apply(&mut *cell),
+            Err(err) => Err(err),
+        };
+        state.report(result)
+    }
+
+    /// Reads one byte, or `None` at end of input
+    fn read_byte(&mut self) -> Result<Option<u8>, &'static str> {
+        let mut byte = 0;
+        loop {
+            match self.input.read(slice::from_mut(&mut byte)) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte)),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err("IO error"),
+            }
+        }
+    }
+
+    /// Turns the result of an extern function into what it returns to the JIT code
+    fn report(&mut self, result: Result<(), &'static str>) -> u8 {
+        match result {
+            Ok(()) => 0,
+            Err(err) => {
+                self.error = Some(err);
+                1
+            }
+        }
+    }
+
+    /// Writes as much of `bytes` as `max_output` leaves room for, like `Vm`
+    fn write_output(&mut self, bytes: &[u8]) -> u8 {
+        let room = self
+            .limits
+            .max_output
+            .map_or(usize::MAX, |max| max - self.output_len);
+        let len = bytes.len().min(room);
+        self.output_len += len;
+        if let Some(capture) = self.captures.last_mut() {
+            capture.extend_from_slice(&bytes[..len]);
+        } else if self.output.write_all(&bytes[..len]).is_err() {
+            self.error = Some("IO error");
+            return 1;
+        }
+        if len < bytes.len() {
+            self.error = Some(limits::OUTPUT_TOO_LARGE);
+            return 1;
+        }
+        0
+    }
+
+    unsafe extern "win64" fn capture_output(state: *mut JitState) -> u8 {
+        (*state).captures.push(vec![]);
+        0
+    }
+
+    unsafe extern "win64" fn expect_output(state: *mut JitState, expected: *const Vec<u8>) -> u8 {
+        let state = &mut *state;
+        if state.captures.last() != Some(&*expected) {
+            state.error = Some(vm::UNEXPECTED_OUTPUT);
+            return 1;
+        }
+        state.captures.pop();
+        0
+    }
+
+    /// Lets the JIT code reach tape `id`, allocated by `run_jit`
+    unsafe extern "win64" fn make_tape(state: *mut JitState, id: usize) -> u8 {
+        let state = &mut *state;
+        let tape = &mut state.tapes[id];
+        *state.tape_table.add(id) = TapeEntry {
+            ptr: tape.as_mut_ptr(),
+            len: tape.len(),
+        };
+        state.made[id] = true;
+        0
+    }
+
+    unsafe extern "win64" fn debug(state: *mut JitState) -> u8 {
+        let state = &mut *state;
+        // only the tapes made so far, like `Vm`
+        let made = state
+            .tapes
+            .iter()
+            .zip(&state.made)
+            .filter(|(_, &made)| made);
+        let mut dump = vec![];
+        for (cells, _) in made {
+            let tape = Tape::from_cells(Some(cells.clone()));
+            let _ = writeln!(dump, "{:#?}", tape);
+        }
+        state.write_output(&dump)
+    }
+
+    unsafe extern "win64" fn check_clock(state: *mut JitState) -> u8 {
+        let state = &mut *state;
+        state.ticks = CLOCK_CHECK_INTERVAL;
+        if state
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            state.error = Some(limits::TIMED_OUT);
+            return 1;
+        }
+        0
+    }
+
+    /// Opcodes executed by the last `run_jit`, counted like `Vm::executed`
+    pub fn executed(&self) -> u64 {
+        self.limits
+            .fuel
+            .unwrap_or(u64::MAX)
+            .saturating_sub(self.fuel)
     }
 
     pub fn new(input: Box<dyn BufRead + 'a>, output: Box<dyn Write + 'a>) -> JitState<'a> {
         JitState {
             input,
             output,
-            tape: [0; TAPE_SIZE],
+            tapes: vec![],
+            limits: Limits::default(),
+            eof: Eof::default(),
+            tape_table: std::ptr::null_mut(),
+            made: vec![],
+            max_tape: u64::MAX,
+            fuel: u64::MAX,
+            calls_left: 0,
+            stack_top: std::ptr::null_mut(),
+            ticks: CLOCK_CHECK_INTERVAL,
+            deadline: None,
+            output_len: 0,
+            captures: vec![],
+            error: None,
         }
     }
 }
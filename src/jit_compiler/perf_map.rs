@@ -0,0 +1,16 @@
+use super::Symbol;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+
+/// Appends `symbols` to `/tmp/perf-<pid>.map`, which is where `perf report`
+/// looks up names for anonymous executable memory.
+pub fn write_perf_map(symbols: &[Symbol]) -> io::Result<()> {
+    let path = format!("/tmp/perf-{}.map", std::process::id());
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut out = BufWriter::new(file);
+
+    for symbol in symbols {
+        writeln!(out, "{:x} {:x} {}", symbol.addr, symbol.size, symbol.name)?;
+    }
+    out.flush()
+}
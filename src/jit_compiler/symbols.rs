@@ -0,0 +1,84 @@
+use super::JitCode;
+use crate::vm::{BracketKind, OpCode, Program};
+use std::path::Path;
+
+/// A named region of JIT-compiled machine code
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub addr: usize,
+    pub size: usize,
+    pub name: String,
+}
+
+impl JitCode {
+    /// Splits the compiled code into one symbol per source line region.
+    /// Regions starting a loop are suffixed with `:loop`, so profilers show
+    /// e.g. `tape:hello.tp:12:loop` instead of an anonymous address, and the
+    /// ones in a proc are prefixed with its name, like `tape:square:hello.tp:3`.
+    /// Code from included files is named after them instead of `file_name`.
+    pub fn symbols(&self, program: &Program, file_name: &str) -> Vec<Symbol> {
+        let base = self.code.ptr(dynasmrt::AssemblyOffset(0)) as usize;
+        let mut symbols = vec![];
+        let mut push = |start: usize, end: usize, name: String| {
+            if end > start {
+                symbols.push(Symbol {
+                    addr: base + start,
+                    size: end - start,
+                    name,
+                });
+            }
+        };
+
+        // the proc every opcode is in, if any
+        let mut procs = vec![None; program.code.len()];
+        for &(ref name, start) in &program.procs {
+            // the body ends where the `SkipProc` right before it jumps to
+            let skip = start.checked_sub(1).and_then(|i| program.code.get(i));
+            if let Some(&OpCode::SkipProc(offset)) = skip {
+                for proc in &mut procs[start..start - 1 + offset] {
+                    *proc = Some(name.as_str());
+                }
+            }
+        }
+
+        let first = self.op_offsets.first().copied().unwrap_or(self.end);
+        push(self.start.0, first, "tape:prologue".to_owned());
+
+        let mut i = 0;
+        while i < self.op_offsets.len() {
+            let line = program.lines[i];
+            let file = program.op_files[i];
+            let proc = procs[i];
+            let is_loop = matches!(program.code[i], OpCode::Jump(_, BracketKind::Close, _));
+
+            let mut j = i + 1;
+            while j < self.op_offsets.len()
+                && program.lines[j] == line
+                && program.op_files[j] == file
+                && procs[j] == proc
+                && !matches!(program.code[j], OpCode::Jump(_, BracketKind::Close, _))
+            {
+                j += 1;
+            }
+
+            let end = self.op_offsets.get(j).copied().unwrap_or(self.end);
+            let path = program.file_name(i, file_name);
+            let file_name = Path::new(&*path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let mut name = match proc {
+                Some(proc) => format!("tape:{}:{}:{}", proc, file_name, line),
+                None => format!("tape:{}:{}", file_name, line),
+            };
+            if is_loop {
+                name.push_str(":loop");
+            }
+            push(self.op_offsets[i], end, name);
+            i = j;
+        }
+
+        push(self.end, self.code.len(), "tape:epilogue".to_owned());
+        symbols
+    }
+}
@@ -0,0 +1,11 @@
+pub mod compiler;
+pub mod jit_compiler;
+pub mod limits;
+pub mod optimizer;
+pub mod parser;
+pub mod scanner;
+pub mod snapshot;
+pub mod stdlib;
+pub mod tape_struct;
+pub mod vm;
+pub mod vm_async;
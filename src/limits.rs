@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+pub const FUEL_EXHAUSTED: &str = "Limit exceeded: instruction fuel exhausted";
+pub const TIMED_OUT: &str = "Limit exceeded: timed out";
+pub const OUTPUT_TOO_LARGE: &str = "Limit exceeded: too much output";
+pub const TAPE_TOO_LARGE: &str = "Limit exceeded: tape too large";
+pub const CALL_TOO_DEEP: &str = "Limit exceeded: calls nested too deep";
+
+/// Unlike the other limits, call depth is always bounded: the JIT nests
+/// calls on the machine stack, 48 bytes each.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Resource limits for running untrusted programs, enforced identically by
+/// `Vm::run` and `run_jit`. `None` means unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of opcodes executed
+    pub fuel: Option<u64>,
+    /// Maximum wall-clock running time
+    pub timeout: Option<Duration>,
+    /// Maximum number of bytes written by `putch`
+    pub max_output: Option<usize>,
+    /// Maximum number of cells a `make` may allocate
+    pub max_tape: Option<usize>,
+    /// Maximum number of `call`s that have not returned yet
+    pub max_call_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: None,
+            timeout: None,
+            max_output: None,
+            max_tape: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
+}
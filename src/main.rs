@@ -1,23 +1,32 @@
-mod compiler;
-mod jit_compiler;
-mod parser;
-mod scanner;
-mod tape_struct;
-mod vm;
-
-use clap::Parser;
-use compiler::Compiler;
-use jit_compiler::{jit_compile, run_jit, JitState};
+mod bench;
+mod doc;
+mod dump;
+mod test_runner;
+
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Parser, Subcommand};
 use std::fs;
 use std::io::stdin;
 use std::io::stdout;
 use std::io::BufReader;
 use std::io::BufWriter;
-use vm::{Program, Vm};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tape::compiler::Compiler;
+#[cfg(feature = "gdb-jit")]
+use tape::jit_compiler::GdbRegistration;
+use tape::jit_compiler::{jit_compile, run_jit, write_perf_map, JitState};
+use tape::limits::{Limits, DEFAULT_MAX_CALL_DEPTH};
+use tape::optimizer::{self, MAX_LEVEL};
+use tape::stdlib;
+use tape::vm::{Eof, Program, Vm};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(
         long,
         short,
@@ -34,45 +43,313 @@ struct Args {
         default_missing_value = "true"
     )]
     verbose: bool,
-    file_path: std::path::PathBuf,
+    /// Optimization level: 1 folds runs of `incr` and `decr`, 2 also turns
+    /// clearing and adding loops into straight-line code, 3 also puts off
+    /// index moves and drops overwritten stores
+    #[arg(short = 'O', value_name = "LEVEL", default_value_t = 0, value_parser = opt_level())]
+    opt_level: u8,
+    /// Write JIT symbols to /tmp/perf-<pid>.map for `perf`
+    #[arg(long, action)]
+    perf_map: bool,
+    /// Register JIT symbols with GDB through its JIT interface
+    #[cfg(feature = "gdb-jit")]
+    #[arg(long, action)]
+    gdb_jit: bool,
+    /// Stop after executing this many instructions
+    #[arg(long, value_name = "INSTRUCTIONS")]
+    fuel: Option<u64>,
+    /// Stop after running for this long
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_timeout,
+        allow_negative_numbers = true
+    )]
+    timeout: Option<Duration>,
+    /// Stop when the program prints more than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_output: Option<usize>,
+    /// Look for included files in this directory too
+    #[arg(short = 'I', value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+    /// Refuse to make tapes larger than this many cells
+    #[arg(long, value_name = "CELLS")]
+    max_tape: Option<usize>,
+    /// Stop when calls nest deeper than this
+    #[arg(long, value_name = "CALLS", default_value_t = DEFAULT_MAX_CALL_DEPTH)]
+    max_call_depth: usize,
+    /// What `getch` and `getnum` store once the input has ended
+    #[arg(
+        long,
+        value_name = "MODE",
+        default_value = "error",
+        value_parser = PossibleValuesParser::new(["zero", "minus-one", "unchanged", "error"])
+            .map(|mode| mode.parse::<Eof>().unwrap())
+    )]
+    eof: Eof,
+    #[arg(required = true)]
+    file_path: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the compiled program instead of running it
+    Dump {
+        /// Opcode listing with jump targets, loop nesting and source lines
+        #[arg(long, action, required_unless_present = "asm")]
+        ir: bool,
+        /// x86-64 disassembly of the JIT-compiled code
+        #[arg(long, action)]
+        asm: bool,
+        /// Optimization level of the program printed
+        #[arg(short = 'O', value_name = "LEVEL", default_value_t = 0, value_parser = opt_level())]
+        opt_level: u8,
+        /// Look for included files in this directory too
+        #[arg(short = 'I', value_name = "DIR")]
+        include_dirs: Vec<PathBuf>,
+        file_path: PathBuf,
+    },
+    /// Print Markdown documentation of a module, from its `#!!` doc comments
+    Doc {
+        /// Look for included files in this directory too
+        #[arg(short = 'I', value_name = "DIR")]
+        include_dirs: Vec<PathBuf>,
+        /// A file, or a standard library module like `std/io.tp`
+        file_path: PathBuf,
+    },
+    /// Run every `*_test.tp` file on the VM, failing on `assert`s and `expect_output`s
+    Test {
+        /// Look for included files in this directory too
+        #[arg(short = 'I', value_name = "DIR")]
+        include_dirs: Vec<PathBuf>,
+        /// Fail a test after executing this many instructions
+        #[arg(long, value_name = "INSTRUCTIONS", default_value_t = 10_000_000)]
+        fuel: u64,
+        /// Test files, or directories to look for them in
+        #[arg(default_value = ".")]
+        paths: Vec<PathBuf>,
+    },
+    /// Time programs on the VM and the JIT, like the workloads in `bench/`
+    Bench {
+        /// Look for included files in this directory too
+        #[arg(short = 'I', value_name = "DIR")]
+        include_dirs: Vec<PathBuf>,
+        /// Times to run each program, after the warmup
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+        runs: u64,
+        /// Untimed runs before those
+        #[arg(long, default_value_t = 1)]
+        warmup: u64,
+        /// Optimization level to time each backend at, once per `-O`
+        #[arg(short = 'O', value_name = "LEVEL", default_value = "0", value_parser = opt_level())]
+        opt_levels: Vec<u8>,
+        /// Programs, or directories to look for `*.tp` files in
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
 }
 
 // TODO: improve the JIT compiler
 
+/// A number of seconds, which must be finite and not negative
+fn parse_timeout(seconds: &str) -> Result<Duration, String> {
+    let seconds: f64 = seconds.parse().map_err(|_| "not a number".to_owned())?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| "not a duration in seconds".to_owned())
+}
+
+fn opt_level() -> clap::builder::RangedI64ValueParser<u8> {
+    clap::value_parser!(u8).range(0..=MAX_LEVEL as i64)
+}
+
 fn main() -> Result<(), &'static str> {
     let args = Args::parse();
 
-    let source_code = fs::read_to_string(args.file_path).expect("failed reading file");
+    if let Some(Command::Dump {
+        ir,
+        asm,
+        opt_level,
+        include_dirs,
+        file_path,
+    }) = &args.command
+    {
+        return dump(file_path, include_dirs, *opt_level, *ir, *asm);
+    }
+    if let Some(Command::Doc {
+        include_dirs,
+        file_path,
+    }) = &args.command
+    {
+        return doc(file_path, include_dirs);
+    }
+    if let Some(Command::Test {
+        include_dirs,
+        fuel,
+        paths,
+    }) = &args.command
+    {
+        return test(paths, include_dirs, *fuel);
+    }
+    if let Some(Command::Bench {
+        include_dirs,
+        runs,
+        warmup,
+        opt_levels,
+        paths,
+    }) = &args.command
+    {
+        let (runs, warmup) = (*runs as usize, *warmup as usize);
+        return bench(paths, include_dirs, opt_levels, runs, warmup);
+    }
+
+    let file_path = args.file_path.as_ref().unwrap();
+    let source_code = fs::read_to_string(file_path).expect("failed reading file");
 
     let mut compiler = Compiler::new(&source_code);
-    let program = compiler.compile();
-    // debug_show(&program);
+    compiler.path = Some(file_path.clone());
+    compiler.include_dirs = args.include_dirs.clone();
+    let program = optimizer::optimize(&compiler.compile(), args.opt_level);
+
+    let limits = Limits {
+        fuel: args.fuel,
+        timeout: args.timeout,
+        max_output: args.max_output,
+        max_tape: args.max_tape,
+        max_call_depth: args.max_call_depth,
+    };
 
     if args.jit {
         if args.verbose {
-            println!("[Using JIT compiler]\n");
+            println!("[Using JIT compiler, -O{}]\n", args.opt_level);
         }
-        jit(&program)
+        jit(&program, &args, limits)
     } else {
         if args.verbose {
-            println!("[Using bytecode VM]\n");
+            println!("[Using bytecode VM, -O{}]\n", args.opt_level);
         }
-        let mut vm = Vm::new();
+        let mut vm = Vm::with_limits(limits);
+        vm.set_eof(args.eof);
         vm.run(&program)
     }
 }
 
-fn jit(p: &Program) -> Result<(), &'static str> {
+fn jit(p: &Program, args: &Args, limits: Limits) -> Result<(), &'static str> {
     let mut state = JitState::new(
         Box::new(BufReader::new(stdin())),
         Box::new(BufWriter::new(stdout())),
     );
+    state.limits = limits;
+    state.eof = args.eof;
+
+    let jit_code = jit_compile(p)?;
 
-    let jit_code = jit_compile(&p)?;
-    run_jit(&mut state, jit_code)
+    let file_name = args
+        .file_path
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .unwrap_or_default()
+        .to_string_lossy();
+    let symbols = jit_code.symbols(p, &file_name);
+    if args.perf_map && write_perf_map(&symbols).is_err() {
+        return Err("failed writing perf map");
+    }
+    #[cfg(feature = "gdb-jit")]
+    let _gdb_registration = args
+        .gdb_jit
+        .then(|| GdbRegistration::new(&symbols, &file_name));
+
+    run_jit(&mut state, &jit_code)
 }
 
-#[allow(dead_code)]
-fn debug_show(p: &Program) {
-    println!("{:#?}", p);
+fn dump(
+    file_path: &Path,
+    include_dirs: &[PathBuf],
+    opt_level: u8,
+    ir: bool,
+    asm: bool,
+) -> Result<(), &'static str> {
+    let source_code = fs::read_to_string(file_path).expect("failed reading file");
+
+    let mut compiler = Compiler::new(&source_code);
+    compiler.path = Some(file_path.to_path_buf());
+    compiler.include_dirs = include_dirs.to_vec();
+    let program = optimizer::optimize(&compiler.compile(), opt_level);
+
+    let mut out = stdout().lock();
+    if ir && dump::dump_ir(&program, &source_code, &mut out).is_err() {
+        return Err("IO error");
+    }
+    if asm {
+        let jit_code = jit_compile(&program)?;
+        if dump::dump_asm(&program, &jit_code, &mut out).is_err() {
+            return Err("IO error");
+        }
+    }
+    Ok(())
+}
+
+fn doc(file_path: &Path, include_dirs: &[PathBuf]) -> Result<(), &'static str> {
+    // standard library modules are documented even without their files at hand
+    let source_code = match fs::read_to_string(file_path) {
+        Ok(source_code) => source_code,
+        Err(_) => match file_path.to_str().and_then(stdlib::module) {
+            Some(module) => module.to_owned(),
+            None => return Err("failed reading file"),
+        },
+    };
+
+    let mut compiler = Compiler::new(&source_code);
+    compiler.path = Some(file_path.to_path_buf());
+    compiler.include_dirs = include_dirs.to_vec();
+    compiler.compile();
+
+    let title = file_path.to_string_lossy();
+    let module_doc = compiler.parser.scanner.module_doc.as_deref();
+    let mut out = stdout().lock();
+    doc::write_doc(&title, module_doc, &compiler.docs, &mut out).map_err(|_| "IO error")
+}
+
+fn test(paths: &[PathBuf], include_dirs: &[PathBuf], fuel: u64) -> Result<(), &'static str> {
+    let mut tests = vec![];
+    for path in paths {
+        test_runner::find_files(path, "_test.tp", &mut tests)
+            .map_err(|_| "failed reading directory")?;
+    }
+    if tests.is_empty() {
+        return Err("no *_test.tp files found");
+    }
+
+    let limits = Limits {
+        fuel: Some(fuel),
+        ..Limits::default()
+    };
+    let mut out = stdout().lock();
+    match test_runner::run_tests(&tests, include_dirs, limits, &mut out) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("some tests failed"),
+        Err(_) => Err("IO error"),
+    }
+}
+
+fn bench(
+    paths: &[PathBuf],
+    include_dirs: &[PathBuf],
+    opt_levels: &[u8],
+    runs: usize,
+    warmup: usize,
+) -> Result<(), &'static str> {
+    let mut programs = vec![];
+    for path in paths {
+        test_runner::find_files(path, ".tp", &mut programs)
+            .map_err(|_| "failed reading directory")?;
+    }
+    if programs.is_empty() {
+        return Err("no *.tp files found");
+    }
+
+    let mut out = stdout().lock();
+    match bench::run_benches(&programs, include_dirs, opt_levels, runs, warmup, &mut out) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("some programs failed"),
+        Err(_) => Err("IO error"),
+    }
 }
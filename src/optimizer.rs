@@ -0,0 +1,431 @@
+//! Passes rewriting a `Program` into one printing the same and failing the
+//! same way in fewer opcodes. Only what a failing program leaves on its tapes,
+//! and how much fuel a program takes, may change.
+
+use crate::vm::{BracketKind, Cell, IdxId, OpCode, Program};
+
+/// The highest level `optimize` takes
+pub const MAX_LEVEL: u8 = 3;
+
+/// Runs every pass up to `level` on the program:
+///
+/// 1. folds runs of `incr` and `decr` on the same cell or index into one opcode
+/// 2. turns loops clearing their cell, or adding it to other cells, into
+///    straight-line code
+/// 3. moves indices only at the end of straight-line code, offsetting the cells
+///    used before that instead, and drops stores overwritten before being read
+pub fn optimize(program: &Program, level: u8) -> Program {
+    let mut program = program.clone();
+    if level >= 3 {
+        program = lower_offsets(&program);
+    }
+    if level >= 1 {
+        program = fold_runs(&program);
+    }
+    if level >= 2 {
+        program = recognize_loops(&program);
+    }
+    if level >= 3 {
+        program = eliminate_dead_stores(&program);
+    }
+    program
+}
+
+/// The new program a pass builds out of the opcodes of the old one, each
+/// coming from an opcode of the old program. Jumps keep their old offsets
+/// until `finish` points them at where their targets ended up.
+struct Rewriter<'a> {
+    program: &'a Program,
+    code: Vec<OpCode>,
+    /// Index in the old program of the opcode each new one comes from
+    origins: Vec<usize>,
+}
+
+impl<'a> Rewriter<'a> {
+    fn new(program: &'a Program) -> Self {
+        Rewriter {
+            program,
+            code: Vec::with_capacity(program.code.len()),
+            origins: Vec::with_capacity(program.code.len()),
+        }
+    }
+
+    fn emit(&mut self, op: OpCode, origin: usize) {
+        self.code.push(op);
+        self.origins.push(origin);
+    }
+
+    fn finish(self) -> Program {
+        // where the code from each old opcode on starts in the new program,
+        // which is where jumps to that opcode land now
+        let mut starts = vec![self.code.len(); self.program.code.len() + 1];
+        for (i, &origin) in self.origins.iter().enumerate().rev() {
+            starts[origin] = starts[origin].min(i);
+        }
+        for origin in (0..self.program.code.len()).rev() {
+            starts[origin] = starts[origin].min(starts[origin + 1]);
+        }
+
+        let code = self
+            .code
+            .iter()
+            .zip(&self.origins)
+            .enumerate()
+            .map(|(i, (&op, &origin))| match op {
+                OpCode::Jump(offset, BracketKind::Close, cell) => {
+                    OpCode::Jump(starts[origin + offset] - i, BracketKind::Close, cell)
+                }
+                OpCode::Jump(offset, BracketKind::Open, cell) => {
+                    OpCode::Jump(i + 1 - starts[origin + 1 - offset], BracketKind::Open, cell)
+                }
+                OpCode::SkipProc(offset) => OpCode::SkipProc(starts[origin + offset] - i),
+                OpCode::BranchIfZero(offset, cell) => {
+                    OpCode::BranchIfZero(starts[origin + offset] - i, cell)
+                }
+                OpCode::Branch(offset) => OpCode::Branch(starts[origin + offset] - i),
+                OpCode::Call(target) => OpCode::Call(starts[target]),
+                op => op,
+            })
+            .collect();
+        Program {
+            code,
+            lines: self
+                .origins
+                .iter()
+                .map(|&i| self.program.lines[i])
+                .collect(),
+            strings: self.program.strings.clone(),
+            files: self.program.files.clone(),
+            op_files: self
+                .origins
+                .iter()
+                .map(|&i| self.program.op_files[i])
+                .collect(),
+            procs: self
+                .program
+                .procs
+                .iter()
+                .map(|(name, start)| (name.clone(), starts[*start]))
+                .collect(),
+        }
+    }
+}
+
+/// Whether a jump lands on each opcode, or on the end of the program
+fn jump_targets(program: &Program) -> Vec<bool> {
+    let mut targets = vec![false; program.code.len() + 1];
+    for (i, op) in program.code.iter().enumerate() {
+        match *op {
+            OpCode::Jump(offset, BracketKind::Close, _)
+            | OpCode::SkipProc(offset)
+            | OpCode::BranchIfZero(offset, _)
+            | OpCode::Branch(offset) => targets[i + offset] = true,
+            OpCode::Jump(offset, BracketKind::Open, _) => targets[i + 1 - offset] = true,
+            OpCode::Call(target) => targets[target] = true,
+            _ => {}
+        }
+    }
+    targets
+}
+
+/// Whether the opcode may jump somewhere else than the next one
+fn is_control(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Jump(..)
+            | OpCode::SkipProc(_)
+            | OpCode::BranchIfZero(..)
+            | OpCode::Branch(_)
+            | OpCode::Call(_)
+            | OpCode::Return
+    )
+}
+
+/// The opcode doing what `first` and then `second` do, if there is one
+fn fold(first: OpCode, second: OpCode) -> Option<OpCode> {
+    use OpCode::*;
+
+    // a move to the left stops at the first cell, so one to the right may not follow it
+    let moves = |a: isize, b: isize| (a >= 0 || b <= 0).then_some(a + b);
+    match (first, second) {
+        (IncrCell(a, n), IncrCell(b, m)) if a == b => Some(IncrCell(a, n.checked_add(m)?)),
+        (DecrCell(a, n), DecrCell(b, m)) if a == b => Some(DecrCell(a, n.checked_add(m)?)),
+        (IncrPtr(a) | DecrPtr(a) | MovePtr(a, _), IncrPtr(b) | DecrPtr(b) | MovePtr(b, _))
+            if a == b =>
+        {
+            let moved = |op| match op {
+                IncrPtr(_) => 1,
+                DecrPtr(_) => -1,
+                MovePtr(_, n) => n,
+                _ => unreachable!(),
+            };
+            Some(MovePtr(a, moves(moved(first), moved(second))?))
+        }
+        _ => None,
+    }
+}
+
+/// Level 1: folds consecutive opcodes changing the same cell, or moving the
+/// same index, the same way into one
+fn fold_runs(program: &Program) -> Program {
+    let targets = jump_targets(program);
+    let mut rewriter = Rewriter::new(program);
+    // whether the last opcode emitted ends right before the current one
+    let mut adjacent = false;
+    for (i, &op) in program.code.iter().enumerate() {
+        let folded = match rewriter.code.last() {
+            Some(&last) if adjacent && !targets[i] => fold(last, op),
+            _ => None,
+        };
+        match folded {
+            // moving by nothing is doing nothing
+            Some(OpCode::MovePtr(_, 0)) => {
+                rewriter.code.pop();
+                rewriter.origins.pop();
+                adjacent = false;
+            }
+            Some(folded) => *rewriter.code.last_mut().unwrap() = folded,
+            None => {
+                rewriter.emit(op, i);
+                adjacent = true;
+            }
+        }
+    }
+    rewriter.finish()
+}
+
+/// Whether two cells are always different ones, wherever the indices are
+fn distinct(a: Cell, b: Cell) -> bool {
+    a.tape != b.tape || (a.idx == b.idx && a.offset != b.offset)
+}
+
+/// Level 2: replaces loops whose body only decrements their cell by one and
+/// adds constants to other cells. One that only decrements its cell just
+/// clears it, and the others keep their first iteration, for it to fail as the
+/// loop would, then do the rest at once with `AddProduct` and `SubProduct`.
+fn recognize_loops(program: &Program) -> Program {
+    let mut rewriter = Rewriter::new(program);
+    let mut i = 0;
+    while i < program.code.len() {
+        let op = program.code[i];
+        let OpCode::Jump(offset, BracketKind::Close, counter) = op else {
+            rewriter.emit(op, i);
+            i += 1;
+            continue;
+        };
+        let end = i + offset - 1;
+        let body = &program.code[i + 1..end];
+
+        let mut decrements = 0;
+        let mut cells = vec![counter];
+        let simple = body.iter().all(|&op| match op {
+            OpCode::DecrCell(cell, 1) if cell == counter => {
+                decrements += 1;
+                true
+            }
+            OpCode::IncrCell(cell, _) | OpCode::DecrCell(cell, _) => {
+                let is_distinct = cells.iter().all(|&other| distinct(cell, other));
+                cells.push(cell);
+                is_distinct
+            }
+            _ => false,
+        });
+        if !simple || decrements != 1 {
+            rewriter.emit(op, i);
+            i += 1;
+            continue;
+        }
+
+        if body.len() == 1 {
+            rewriter.emit(OpCode::SetCell(counter, 0), i);
+        } else {
+            rewriter.emit(op, i);
+            for (j, &op) in body.iter().enumerate() {
+                rewriter.emit(op, i + 1 + j);
+            }
+            for (j, &op) in body.iter().enumerate() {
+                match op {
+                    OpCode::IncrCell(cell, n) if cell != counter => {
+                        rewriter.emit(OpCode::AddProduct(cell, counter, n), i + 1 + j)
+                    }
+                    OpCode::DecrCell(cell, n) if cell != counter => {
+                        rewriter.emit(OpCode::SubProduct(cell, counter, n), i + 1 + j)
+                    }
+                    _ => {}
+                }
+            }
+            rewriter.emit(OpCode::SetCell(counter, 0), end - 1);
+            rewriter.emit(program.code[end], end);
+        }
+        i = end + 1;
+    }
+    rewriter.finish()
+}
+
+/// The cell an opcode reads or writes, if any
+fn cell_mut(op: &mut OpCode) -> Option<&mut Cell> {
+    use OpCode::*;
+
+    match op {
+        IncrCell(cell, _)
+        | DecrCell(cell, _)
+        | SetCell(cell, _)
+        | PrintChar(cell)
+        | GetChar(cell)
+        | PrintUtf8(cell)
+        | PrintWideUtf8(cell)
+        | PrintNum(cell, _)
+        | GetNum(cell)
+        | Assert(cell, _)
+        | Jump(_, _, cell)
+        | BranchIfZero(_, cell) => Some(cell),
+        _ => None,
+    }
+}
+
+/// How far `idx` is behind where it should be
+fn moved_mut(pending: &mut Vec<isize>, idx: IdxId) -> &mut isize {
+    if pending.len() <= idx {
+        pending.resize(idx + 1, 0);
+    }
+    &mut pending[idx]
+}
+
+/// Level 3: leaves index moves for the end of straight-line code, using the
+/// cells past where the index is instead. A move to the left is only put off
+/// while it can't reach the first cell, where it would stop.
+fn lower_offsets(program: &Program) -> Program {
+    let targets = jump_targets(program);
+    let mut rewriter = Rewriter::new(program);
+    // how far each index is behind where it should be
+    let mut pending: Vec<isize> = vec![];
+    let flush = |rewriter: &mut Rewriter, pending: &mut Vec<isize>, origin| {
+        for (idx, moved) in pending.iter_mut().enumerate() {
+            match *moved {
+                0 => {}
+                1 => rewriter.emit(OpCode::IncrPtr(idx), origin),
+                n => rewriter.emit(OpCode::MovePtr(idx, n), origin),
+            }
+            *moved = 0;
+        }
+    };
+
+    for (i, &op) in program.code.iter().enumerate() {
+        // code jumping here has moved its indices already
+        if targets[i] && i > 0 {
+            flush(&mut rewriter, &mut pending, i - 1);
+        }
+        match op {
+            OpCode::IncrPtr(idx) => *moved_mut(&mut pending, idx) += 1,
+            OpCode::DecrPtr(idx) | OpCode::MovePtr(idx, _) => {
+                let n = match op {
+                    OpCode::MovePtr(_, n) => n,
+                    _ => -1,
+                };
+                let moved = moved_mut(&mut pending, idx);
+                if n >= 0 || *moved + n >= 0 {
+                    *moved += n;
+                } else {
+                    // an index moved right by `moved` first can't stop at the first cell
+                    match *moved + n {
+                        -1 => rewriter.emit(OpCode::DecrPtr(idx), i),
+                        n => rewriter.emit(OpCode::MovePtr(idx, n), i),
+                    }
+                    *moved = 0;
+                }
+            }
+            OpCode::SetPtr(idx, _) => {
+                *moved_mut(&mut pending, idx) = 0;
+                rewriter.emit(op, i);
+            }
+            op if is_control(op) => {
+                flush(&mut rewriter, &mut pending, i);
+                rewriter.emit(op, i);
+            }
+            mut op => {
+                // an offset the move would overflow leaves the op as it is,
+                // once the indices are where it expects them
+                let original = op;
+                let mut fits = true;
+                let mut lower = |cell: &mut Cell| {
+                    if let Some(idx) = cell.idx {
+                        match cell.offset_by(*moved_mut(&mut pending, idx)) {
+                            Some(lowered) => *cell = lowered,
+                            None => fits = false,
+                        }
+                    }
+                };
+                match &mut op {
+                    OpCode::AddProduct(cell, factor, _) | OpCode::SubProduct(cell, factor, _) => {
+                        lower(cell);
+                        lower(factor);
+                    }
+                    // as does one leaving the last cell of a wide character too far
+                    OpCode::PrintWideUtf8(cell) => {
+                        lower(cell);
+                        fits &= cell.offset_by(2).is_some();
+                    }
+                    op => {
+                        if let Some(cell) = cell_mut(op) {
+                            lower(cell);
+                        }
+                    }
+                }
+                if !fits {
+                    flush(&mut rewriter, &mut pending, i);
+                    op = original;
+                }
+                rewriter.emit(op, i);
+            }
+        }
+    }
+    if let Some(last) = program.code.len().checked_sub(1) {
+        flush(&mut rewriter, &mut pending, last);
+    }
+    rewriter.finish()
+}
+
+/// Level 3: drops stores to a cell overwritten before anything else but
+/// other stores runs, and folds additions right after a store into it
+fn eliminate_dead_stores(program: &Program) -> Program {
+    let targets = jump_targets(program);
+    let mut rewriter = Rewriter::new(program);
+    for (i, &op) in program.code.iter().enumerate() {
+        let stored = match (rewriter.code.last(), op) {
+            (Some(&OpCode::SetCell(a, n)), OpCode::IncrCell(b, m)) if !targets[i] && a == b => {
+                n.checked_add(m)
+            }
+            (Some(&OpCode::SetCell(a, n)), OpCode::DecrCell(b, m)) if !targets[i] && a == b => {
+                n.checked_sub(m)
+            }
+            _ => None,
+        };
+        if let (Some(value), Some(OpCode::SetCell(_, n))) = (stored, rewriter.code.last_mut()) {
+            *n = value;
+            continue;
+        }
+
+        if let OpCode::SetCell(cell, _) = op {
+            // a store fails like the one overwriting it, if anything,
+            // and the stores in between fail the same way
+            let overwritten = rewriter
+                .code
+                .iter()
+                .rev()
+                .map_while(|&op| match op {
+                    OpCode::SetCell(other, _) if other == cell || distinct(other, cell) => {
+                        Some(other)
+                    }
+                    _ => None,
+                })
+                .position(|other| other == cell);
+            if let Some(back) = overwritten {
+                let index = rewriter.code.len() - 1 - back;
+                rewriter.code.remove(index);
+                rewriter.origins.remove(index);
+            }
+        }
+        rewriter.emit(op, i);
+    }
+    rewriter.finish()
+}
@@ -1,48 +1,69 @@
-use crate::scanner::{Scanner, Token, TokenType};
+use crate::scanner::{Expansion, Origin, Scanner, Token, TokenType};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
 
 pub struct Parser {
     pub current: Token,
     pub previous: Token,
     pub scanner: Scanner,
+    /// Tokens of macro expansions, read before the scanner's, last first
+    pending: Vec<Token>,
+    /// The main source, for showing where errors are
+    source: String,
+    /// The source of every included file, by the path its tokens point to
+    pub sources: HashMap<Rc<Path>, Rc<str>>,
 }
 
 impl Parser {
     pub fn new(scanner: Scanner) -> Self {
         Self {
+            source: scanner.source.iter().collect(),
             scanner,
             previous: Token::default(),
             current: Token::default(),
+            pending: vec![],
+            sources: HashMap::new(),
         }
     }
 
-    pub fn advance(&mut self) {
+    pub fn advance(&mut self) -> Result<(), CompileError> {
         self.previous = self.current.clone();
 
-        loop {
-            self.current = self.scanner.scan_token();
-            if self.current.typ != TokenType::Error {
-                break;
-            }
-
-            self.error_at_current(&self.current.lexeme.clone());
+        self.current = match self.pending.pop() {
+            Some(token) => token,
+            None => self.scanner.scan_token(),
+        };
+        if self.current.typ == TokenType::Error {
+            return self.error_at_current(&self.current.lexeme);
         }
+        Ok(())
     }
 
-    pub fn consume(&mut self, typ: TokenType) {
+    pub fn consume(&mut self, typ: TokenType) -> Result<(), CompileError> {
         if self.current.typ == typ {
-            self.advance();
+            self.advance()
         } else {
-            let msg = &format!("Expected `[`, found: {}", self.current.lexeme);
-            self.error_at_current(msg);
+            let msg = &format!("Expected {}, found: {}", typ, self.found());
+            self.error_at_current(msg)
         }
     }
 
-    pub fn consume_fixed(&mut self, typ: TokenType, val: &str) {
-        if self.current.typ == typ && &self.current.lexeme == val {
-            self.advance();
+    pub fn consume_fixed(&mut self, typ: TokenType, val: &str) -> Result<(), CompileError> {
+        if self.current.typ == typ && self.current.lexeme == val {
+            self.advance()
         } else {
-            let msg = &format!("Expected `[`, found: {}", self.current.lexeme);
-            self.error_at_current(msg);
+            let msg = &format!("Expected `{}`, found: {}", val, self.found());
+            self.error_at_current(msg)
+        }
+    }
+
+    /// The current token as errors about expecting another one show it
+    fn found(&self) -> String {
+        match self.current.typ {
+            TokenType::EOF => self.current.typ.to_string(),
+            _ => self.current.lexeme.clone(),
         }
     }
 
@@ -50,16 +71,127 @@ impl Parser {
         self.current.typ == typ
     }
 
-    pub fn matches(&mut self, typ: TokenType) -> bool {
+    /// The token after the current one, read ahead without consuming anything
+    pub fn peek(&mut self) -> &Token {
+        if self.pending.is_empty() {
+            let token = self.scanner.scan_token();
+            self.pending.push(token);
+        }
+        &self.pending[self.pending.len() - 1]
+    }
+
+    pub fn matches(&mut self, typ: TokenType) -> Result<bool, CompileError> {
         if !self.check(typ) {
-            return false;
+            return Ok(false);
         }
-        self.advance();
-        true
+        self.advance()?;
+        Ok(true)
+    }
+
+    pub fn error_at_current<T>(&self, err: &str) -> Result<T, CompileError> {
+        self.error_at(&self.current, err)
     }
 
-    pub fn error_at_current(&self, err: &str) -> ! {
-        eprintln!("ERROR: {} at line {}", err, self.current.line);
-        std::process::exit(1);
+    /// Makes `tokens` the next ones to parse, followed by the current token
+    pub fn expand(&mut self, tokens: Vec<Token>) {
+        self.pending.push(self.current.clone());
+        self.pending.extend(tokens.into_iter().rev());
+        self.current = self.pending.pop().unwrap();
+    }
+
+    /// The error at `token`, for returning up to `Compiler::try_compile`
+    pub fn error_at<T>(&self, token: &Token, err: &str) -> Result<T, CompileError> {
+        let snippet = self.snippet(token);
+        let column = snippet
+            .as_ref()
+            .map(|(column, _)| format!(", column {}", column))
+            .unwrap_or_default();
+        let mut msg = format!(
+            "{} at line {}{}{}",
+            err,
+            token.line,
+            column,
+            in_file(&token.expansion)
+        );
+        if let Some((_, snippet)) = snippet {
+            msg.push('\n');
+            msg.push_str(&snippet);
+        }
+        let mut expansion = token.expansion.as_deref();
+        while let Some(used) = expansion {
+            let trace = match &used.origin {
+                Origin::Macro {
+                    name,
+                    defined_at,
+                    defined_in,
+                } => format!(
+                    "  in macro `{}` defined at line {}{}, used at line {}{}",
+                    name,
+                    defined_at,
+                    defined_in
+                        .as_ref()
+                        .map(|path| format!(" of {}", path.display()))
+                        .unwrap_or_default(),
+                    used.used_at,
+                    in_file(&used.parent)
+                ),
+                Origin::Include { path } => format!(
+                    "  in {} included at line {}{}",
+                    path.display(),
+                    used.used_at,
+                    in_file(&used.parent)
+                ),
+            };
+            msg.push('\n');
+            msg.push_str(&trace);
+            expansion = used.parent.as_deref();
+        }
+        Err(CompileError(msg))
+    }
+
+    /// The column `token` starts at, and the line it is on with carets under
+    /// it, found from its span in the source it was scanned from
+    fn snippet(&self, token: &Token) -> Option<(usize, String)> {
+        let source = match token.expansion.as_ref().and_then(|e| e.file()) {
+            Some(path) => self.sources.get(path)?.as_ref(),
+            None => self.source.as_str(),
+        };
+        let before = source.get(..token.start)?;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[token.start..]
+            .find('\n')
+            .map_or(source.len(), |i| token.start + i);
+        let line = source[line_start..line_end].trim_end_matches('\r');
+        let text = source.get(token.start..token.start + token.len)?;
+
+        // tabs stay tabs so the carets line up however wide they are shown
+        let indent: String = before[line_start..]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = text.lines().next().unwrap_or("").chars().count().max(1);
+        let column = before[line_start..].chars().count() + 1;
+        Some((
+            column,
+            format!("    {}\n    {}{}", line, indent, "^".repeat(width)),
+        ))
+    }
+}
+
+/// The first error in a program, with the macro uses and includes it came through
+#[derive(Debug, Clone)]
+pub struct CompileError(pub String);
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// ` of <file>` for lines outside the main file
+fn in_file(expansion: &Option<Rc<Expansion>>) -> String {
+    match expansion.as_ref().and_then(|expansion| expansion.file()) {
+        Some(path) => format!(" of {}", path.display()),
+        None => String::new(),
     }
 }
@@ -1,3 +1,8 @@
+use std::fmt;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     // keywords
@@ -6,62 +11,194 @@ pub enum TokenType {
     Decr,
     Getch,
     Putch,
+    Getnum,
+    Putnum,
+    Putu,
     Loop,
     Debug,
+    Set,
+    By,
+    Print,
+    Proc,
+    Call,
+    Macro,
+    Include,
+    If,
+    Else,
+    Goto,
+    Alias,
+    Assert,
+    ExpectOutput,
     // symbols
     LeftBrace,
     RightBrace,
     LeftParen,
     RightParen,
     Colon,
+    Comma,
     Plus,
+    Minus,
+    Equal,
+    EqualEqual,
     // other
     Number,
+    Char,
+    String,
     Ident,
     Error,
+    #[allow(clippy::upper_case_acronyms)]
     EOF,
 }
 
+/// Every keyword, and the token it is scanned as instead of an identifier
+const KEYWORDS: &[(&str, TokenType)] = &[
+    ("make", TokenType::Make),
+    ("incr", TokenType::Incr),
+    ("decr", TokenType::Decr),
+    ("getch", TokenType::Getch),
+    ("putch", TokenType::Putch),
+    ("getnum", TokenType::Getnum),
+    ("putnum", TokenType::Putnum),
+    ("putu", TokenType::Putu),
+    ("loop", TokenType::Loop),
+    ("debug", TokenType::Debug),
+    ("set", TokenType::Set),
+    ("by", TokenType::By),
+    ("print", TokenType::Print),
+    ("proc", TokenType::Proc),
+    ("call", TokenType::Call),
+    ("macro", TokenType::Macro),
+    ("include", TokenType::Include),
+    ("if", TokenType::If),
+    ("else", TokenType::Else),
+    ("goto", TokenType::Goto),
+    ("alias", TokenType::Alias),
+    ("assert", TokenType::Assert),
+    ("expect_output", TokenType::ExpectOutput),
+];
+
+/// Every single-character token
+const SYMBOLS: &[(char, TokenType)] = &[
+    ('+', TokenType::Plus),
+    ('-', TokenType::Minus),
+    ('=', TokenType::Equal),
+    (':', TokenType::Colon),
+    (',', TokenType::Comma),
+    ('[', TokenType::LeftBrace),
+    (']', TokenType::RightBrace),
+    ('(', TokenType::LeftParen),
+    (')', TokenType::RightParen),
+];
+
+/// How errors name a token they expected
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((keyword, _)) = KEYWORDS.iter().find(|(_, typ)| typ == self) {
+            return write!(f, "`{}`", keyword);
+        }
+        if let Some((symbol, _)) = SYMBOLS.iter().find(|(_, typ)| typ == self) {
+            return write!(f, "`{}`", symbol);
+        }
+        let name = match self {
+            TokenType::EqualEqual => "`==`",
+            TokenType::Number => "a number",
+            TokenType::Char => "a character",
+            TokenType::String => "a string",
+            TokenType::Ident => "a name",
+            TokenType::EOF => "the end of the file",
+            _ => "a token",
+        };
+        f.write_str(name)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub typ: TokenType,
     pub lexeme: String,
-    #[allow(unused)]
+    /// Byte offset of the token in its source
     pub start: usize,
-    #[allow(unused)]
+    /// Length of the token in bytes
     pub len: usize,
     pub line: u32,
+    /// The macro use this token was expanded from, if any
+    pub expansion: Option<Rc<Expansion>>,
+    /// The `#!!` doc comment right before this token, if any
+    pub doc: Option<Rc<str>>,
+}
+
+/// One use of a macro or an include, for pointing errors in the tokens it
+/// brought in at where they came from
+#[derive(Debug)]
+pub struct Expansion {
+    pub origin: Origin,
+    pub used_at: u32,
+    /// The expansion the use itself came from, when macros use macros or files include files
+    pub parent: Option<Rc<Expansion>>,
+}
+
+#[derive(Debug)]
+pub enum Origin {
+    Macro {
+        name: String,
+        defined_at: u32,
+        /// The included file the macro is defined in, if any
+        defined_in: Option<Rc<Path>>,
+    },
+    Include {
+        path: Rc<Path>,
+    },
+}
+
+impl Expansion {
+    /// The file the tokens of this expansion were written in, if not the main one
+    pub fn file(&self) -> Option<&Rc<Path>> {
+        match &self.origin {
+            Origin::Macro { defined_in, .. } => defined_in.as_ref(),
+            Origin::Include { path } => Some(path),
+        }
+    }
 }
 
 impl Token {
     pub fn new(scanner: &Scanner, typ: TokenType) -> Self {
         Self {
             typ,
-            start: scanner.start,
-            len: scanner.current - scanner.start,
+            start: scanner.start_byte,
+            len: scanner.current_byte - scanner.start_byte,
             line: scanner.line,
             lexeme: scanner.source[scanner.start..scanner.current]
                 .iter()
                 .collect(),
+            expansion: None,
+            doc: None,
         }
     }
 
-    pub fn default() -> Self {
+    /// A token standing for a scanning error, with `msg` as its lexeme
+    pub fn error(msg: &str) -> Token {
         Token {
             start: 0,
             line: 0,
             len: 0,
-            typ: TokenType::Ident,
-            lexeme: "<default>".to_owned(),
+            typ: TokenType::Error,
+            lexeme: msg.to_owned(),
+            expansion: None,
+            doc: None,
         }
     }
-    pub fn error(start: usize, line: u32, msg: &str) -> Token {
+}
+
+impl Default for Token {
+    fn default() -> Self {
         Token {
-            start,
-            line,
-            len: msg.len(),
-            typ: TokenType::Error,
-            lexeme: msg.to_owned(),
+            start: 0,
+            line: 0,
+            len: 0,
+            typ: TokenType::Ident,
+            lexeme: "<default>".to_owned(),
+            expansion: None,
+            doc: None,
         }
     }
 }
@@ -70,8 +207,22 @@ pub struct Scanner {
     pub source: Vec<char>,
     pub start: usize,
     pub current: usize,
+    /// Byte offsets of `start` and `current` in the source
+    pub start_byte: usize,
+    pub current_byte: usize,
     pub line: u32,
+    /// The line `start` is on
+    pub start_line: u32,
     pub len: usize,
+    /// Lines of the `#!!` doc comment being read, for the next token
+    doc: Vec<String>,
+    /// Whether nothing but whitespace and comments is on the line so far
+    blank_line: bool,
+    /// Whether a token has been scanned yet
+    started: bool,
+    /// The doc comment at the top of the file, kept apart from the first
+    /// definition by a blank line
+    pub module_doc: Option<String>,
 }
 
 impl Scanner {
@@ -80,18 +231,40 @@ impl Scanner {
             source: source.chars().collect(),
             start: 0,
             current: 0,
+            start_byte: 0,
+            current_byte: 0,
             line: 1,
-            len: source.len(),
+            start_line: 1,
+            len: source.chars().count(),
+            doc: vec![],
+            blank_line: true,
+            started: false,
+            module_doc: None,
         }
     }
 
     pub fn scan_token(&mut self) -> Token {
-        self.skip_whitespaces();
+        if let Some(error) = self.skip_whitespaces() {
+            return error;
+        }
+        self.started = true;
+        self.blank_line = false;
 
+        let doc = mem::take(&mut self.doc);
+        let mut token = self.token();
+        if !doc.is_empty() {
+            token.doc = Some(doc.join("\n").into());
+        }
+        token
+    }
+
+    fn token(&mut self) -> Token {
         self.start = self.current;
+        self.start_byte = self.current_byte;
+        self.start_line = self.line;
 
         if self.is_at_end() {
-            return Token::new(&self, TokenType::EOF);
+            return Token::new(self, TokenType::EOF);
         }
 
         let c = self.advance();
@@ -104,36 +277,94 @@ impl Scanner {
         }
 
         match c {
-            '+' => Token::new(&self, TokenType::Plus),
-            ':' => Token::new(&self, TokenType::Colon),
-            '[' => Token::new(&self, TokenType::LeftBrace),
-            ']' => Token::new(&self, TokenType::RightBrace),
-            '(' => Token::new(&self, TokenType::LeftParen),
-            ')' => Token::new(&self, TokenType::RightParen),
-            _ => Token::error(self.start, self.line, "Unexpected character."),
+            '\'' => self.char_literal(),
+            '"' => self.string(),
+            '=' if self.peek() == '=' => {
+                self.advance();
+                Token::new(self, TokenType::EqualEqual)
+            }
+            _ => match SYMBOLS.iter().find(|&&(symbol, _)| symbol == c) {
+                Some(&(_, typ)) => Token::new(self, typ),
+                None => self.error(&format!("Unexpected character `{}`", c)),
+            },
         }
     }
 
-    fn skip_whitespaces(&mut self) {
+    /// Skips whitespace and comments, gathering doc comments, and returns an
+    /// error token for an unterminated block comment
+    fn skip_whitespaces(&mut self) -> Option<Token> {
         loop {
             match self.peek() {
                 ' ' | '\r' | '\t' => {
                     self.advance();
                 }
                 '\n' => {
+                    // a blank line ends a doc comment without anything to document
+                    if self.blank_line && !self.doc.is_empty() {
+                        let doc = mem::take(&mut self.doc).join("\n");
+                        if !self.started && self.module_doc.is_none() {
+                            self.module_doc = Some(doc);
+                        }
+                    }
                     self.line += 1;
+                    self.blank_line = true;
                     self.advance();
                 }
-                '#' => {
-                    if self.peek_next() == '!' {
-                        while self.peek() != '\n' && !self.is_at_end() {
+                '#' if self.peek_next() == '!' => {
+                    self.blank_line = false;
+                    self.advance();
+                    self.advance();
+                    let doc = self.peek() == '!';
+                    if doc {
+                        self.advance();
+                        if self.peek() == ' ' {
                             self.advance();
                         }
-                    } else {
-                        return;
+                    }
+                    let start = self.current;
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
+                    if doc {
+                        let text = self.source[start..self.current].iter().collect::<String>();
+                        self.doc.push(text.trim_end().to_owned());
+                    }
+                }
+                '#' if self.peek_next() == '{' => {
+                    self.blank_line = false;
+                    if let Some(error) = self.block_comment() {
+                        return Some(error);
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// Skips a `#{ ... }#` comment, which may hold other block comments
+    fn block_comment(&mut self) -> Option<Token> {
+        self.start = self.current;
+        self.start_byte = self.current_byte;
+        self.start_line = self.line;
+        let mut depth = 0;
+        loop {
+            if self.is_at_end() {
+                return Some(self.error("Unterminated block comment"));
+            }
+            match self.advance() {
+                '#' if self.peek() == '{' => {
+                    self.advance();
+                    depth += 1;
+                }
+                '}' if self.peek() == '#' => {
+                    self.advance();
+                    depth -= 1;
+                    if depth == 0 {
+                        return None;
                     }
                 }
-                _ => return,
+                '\n' => self.line += 1,
+                _ => {}
             }
         }
     }
@@ -143,52 +374,68 @@ impl Scanner {
             self.advance();
         }
 
-        Token::new(&self, TokenType::Number)
+        Token::new(self, TokenType::Number)
     }
 
-    fn identifier(&mut self) -> Token {
-        while self.is_alpha(self.peek()) || self.is_digit(self.peek()) {
+    /// Scans `'A'` or an escape like `'\n'`, the lexeme keeps the quotes
+    fn char_literal(&mut self) -> Token {
+        if self.peek() == '\\' {
             self.advance();
         }
-        Token::new(&self, self.identifier_type())
-    }
-
-    fn identifier_type(&self) -> TokenType {
-        match self.source[self.start] {
-            'm' => self.check_keyword(1, "ake", TokenType::Make),
-            'i' => self.check_keyword(1, "ncr", TokenType::Incr),
-            'g' => self.check_keyword(1, "etch", TokenType::Getch),
-            'p' => self.check_keyword(1, "utch", TokenType::Putch),
-            'l' => self.check_keyword(1, "oop", TokenType::Loop),
-            'd' => {
-                if self.current - self.start > 1 {
-                    match self.source[self.start + 1] {
-                        'e' => match self.source[self.start + 2] {
-                            'b' => self.check_keyword(3, "ug", TokenType::Debug),
-                            'c' => self.check_keyword(3, "r", TokenType::Decr),
-                            _ => TokenType::Ident,
-                        },
-                        _ => TokenType::Ident,
-                    }
-                } else {
-                    TokenType::Ident
+        if self.is_at_end() || self.peek() == '\n' {
+            return self.error("Unterminated char literal");
+        }
+        self.advance();
+
+        if self.peek() != '\'' {
+            return self.error("Unterminated char literal");
+        }
+        self.advance();
+        Token::new(self, TokenType::Char)
+    }
+
+    /// Scans `"..."`, which may span lines, the lexeme keeps the quotes
+    fn string(&mut self) -> Token {
+        while self.peek() != '"' && !self.is_at_end() {
+            match self.advance() {
+                '\n' => self.line += 1,
+                '\\' if !self.is_at_end() => {
+                    self.advance();
                 }
+                _ => {}
             }
+        }
 
-            _ => TokenType::Ident,
+        if self.is_at_end() {
+            return self.error("Unterminated string");
         }
+        self.advance();
+        Token::new(self, TokenType::String)
     }
 
-    fn check_keyword(&self, start: usize, rest: &str, ty: TokenType) -> TokenType {
-        let length = rest.len();
-        let start_index = self.start + start;
-        let end_index = start_index + length;
-        let substr: String = self.source[start_index..end_index].into_iter().collect();
+    fn identifier(&mut self) -> Token {
+        while self.is_alpha(self.peek()) || self.peek().is_numeric() {
+            self.advance();
+        }
 
-        if (self.current - self.start == start + length) && (substr == rest) {
-            return ty;
+        let mut token = Token::new(self, TokenType::Ident);
+        if let Some(&(_, typ)) = KEYWORDS
+            .iter()
+            .find(|(keyword, _)| *keyword == token.lexeme)
+        {
+            token.typ = typ;
+        }
+        token
+    }
+
+    /// An error token spanning what was scanned since the token started
+    fn error(&self, msg: &str) -> Token {
+        Token {
+            start: self.start_byte,
+            len: self.current_byte - self.start_byte,
+            line: self.start_line,
+            ..Token::error(msg)
         }
-        TokenType::Ident
     }
 
     fn peek(&self) -> char {
@@ -199,15 +446,17 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
+        if self.current + 1 >= self.len {
             return '\0';
         }
         self.source[self.current + 1]
     }
 
     fn advance(&mut self) -> char {
+        let c = self.source[self.current];
         self.current += 1;
-        self.source[self.current - 1]
+        self.current_byte += c.len_utf8();
+        c
     }
 
     //    pub fn match_token(&mut self, expected: char) -> bool {
@@ -220,11 +469,11 @@ impl Scanner {
     //    }
 
     fn is_alpha(&self, c: char) -> bool {
-        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+        c.is_alphabetic() || c == '_'
     }
 
     fn is_digit(&self, c: char) -> bool {
-        c >= '0' && c <= '9'
+        c.is_ascii_digit()
     }
 
     fn is_at_end(&self) -> bool {
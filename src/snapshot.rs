@@ -0,0 +1,183 @@
+use crate::vm::Eof;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"TAPESNAP";
+const VERSION: u8 = 6;
+
+/// `Eof` modes by the byte they are serialized as
+const EOFS: [Eof; 4] = [Eof::Zero, Eof::MinusOne, Eof::Unchanged, Eof::Error];
+
+/// The state of a paused `Vm`: enough to resume it later, possibly in
+/// another process, on the same `Program`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The cells of every tape, or `None` for those not made yet
+    pub tapes: Vec<Option<Vec<u8>>>,
+    /// The value of every index variable, indexed by `IdxId`
+    pub indices: Vec<usize>,
+    /// Index of the next opcode to execute
+    pub ip: usize,
+    /// Return addresses of the procs being called
+    pub call_stack: Vec<usize>,
+    /// Instructions executed so far, charged against the fuel limit
+    pub executed: u64,
+    /// Time spent running so far, charged against the timeout
+    pub elapsed: Duration,
+    /// Bytes printed so far, charged against the output limit
+    pub output_len: usize,
+    /// Input pushed to the `Vm` but not read by `getch` yet
+    pub input: Vec<u8>,
+    /// Whether the host has said no more input is coming
+    pub input_closed: bool,
+    /// What `getch` and `getnum` do once the input has ended
+    pub eof: Eof,
+    /// Output of the `expect_output` blocks being run, innermost last
+    pub captures: Vec<Vec<u8>>,
+}
+
+impl Snapshot {
+    /// Serializes the snapshot into a self-describing little-endian format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let tapes_len: usize = self.tapes.iter().flatten().map(|tape| tape.len() + 9).sum();
+        let captures_len: usize = self.captures.iter().map(|capture| capture.len() + 8).sum();
+        let mut bytes = Vec::with_capacity(
+            80 + 8 * (self.indices.len() + self.call_stack.len())
+                + tapes_len
+                + self.input.len()
+                + captures_len,
+        );
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&(self.ip as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.executed.to_le_bytes());
+        bytes.extend_from_slice(&self.elapsed.as_secs().to_le_bytes());
+        bytes.extend_from_slice(&self.elapsed.subsec_nanos().to_le_bytes());
+        bytes.extend_from_slice(&(self.output_len as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.indices.len() as u64).to_le_bytes());
+        for &index in &self.indices {
+            bytes.extend_from_slice(&(index as u64).to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.call_stack.len() as u64).to_le_bytes());
+        for &address in &self.call_stack {
+            bytes.extend_from_slice(&(address as u64).to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.tapes.len() as u64).to_le_bytes());
+        for tape in &self.tapes {
+            match tape {
+                Some(tape) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&(tape.len() as u64).to_le_bytes());
+                    bytes.extend_from_slice(tape);
+                }
+                None => bytes.push(0),
+            }
+        }
+        bytes.extend_from_slice(&(self.input.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.input);
+        bytes.push(self.input_closed as u8);
+        bytes.push(EOFS.iter().position(|&eof| eof == self.eof).unwrap() as u8);
+        bytes.extend_from_slice(&(self.captures.len() as u64).to_le_bytes());
+        for capture in &self.captures {
+            bytes.extend_from_slice(&(capture.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(capture);
+        }
+        bytes
+    }
+
+    /// Deserializes a snapshot made by `to_bytes`, checking that it is one
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, &'static str> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("Not a tape snapshot");
+        }
+        if reader.take(1)?[0] != VERSION {
+            return Err("Unsupported snapshot version");
+        }
+
+        let ip = reader.usize()?;
+        let executed = reader.u64()?;
+        let secs = reader.u64()?;
+        let nanos = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+        if nanos >= 1_000_000_000 {
+            return Err("Corrupted snapshot");
+        }
+        let elapsed = Duration::new(secs, nanos);
+        let output_len = reader.usize()?;
+        let mut indices = vec![];
+        for _ in 0..reader.usize()? {
+            indices.push(reader.usize()?);
+        }
+        let mut call_stack = vec![];
+        for _ in 0..reader.usize()? {
+            call_stack.push(reader.usize()?);
+        }
+        let mut tapes = vec![];
+        for _ in 0..reader.usize()? {
+            let tape = match reader.take(1)?[0] {
+                0 => None,
+                1 => {
+                    let len = reader.usize()?;
+                    Some(reader.take(len)?.to_vec())
+                }
+                _ => return Err("Corrupted snapshot"),
+            };
+            tapes.push(tape);
+        }
+        let len = reader.usize()?;
+        let input = reader.take(len)?.to_vec();
+        let input_closed = match reader.take(1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err("Corrupted snapshot"),
+        };
+        let eof = *EOFS
+            .get(reader.take(1)?[0] as usize)
+            .ok_or("Corrupted snapshot")?;
+        let mut captures = vec![];
+        for _ in 0..reader.usize()? {
+            let len = reader.usize()?;
+            captures.push(reader.take(len)?.to_vec());
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err("Corrupted snapshot");
+        }
+        Ok(Snapshot {
+            tapes,
+            indices,
+            ip,
+            call_stack,
+            executed,
+            elapsed,
+            output_len,
+            input,
+            input_closed,
+            eof,
+            captures,
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.bytes.len() < n {
+            return Err("Truncated snapshot");
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, &'static str> {
+        usize::try_from(self.u64()?).map_err(|_| "Corrupted snapshot")
+    }
+}
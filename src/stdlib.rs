@@ -0,0 +1,13 @@
+/// Modules bundled in the binary, which `include "std/<name>.tp"` finds
+/// when no file of that name is found first
+const MODULES: &[(&str, &str)] = &[
+    ("std/io.tp", include_str!("../std/io.tp")),
+    ("std/mem.tp", include_str!("../std/mem.tp")),
+];
+
+pub fn module(name: &str) -> Option<&'static str> {
+    MODULES
+        .iter()
+        .find(|(module, _)| *module == name)
+        .map(|(_, source)| *source)
+}
@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 use std::ops::{Index, IndexMut};
 
+#[derive(Default)]
 pub struct Tape {
     array: Option<Vec<u8>>,
 }
@@ -10,17 +11,26 @@ impl Tape {
         Tape { array: None }
     }
 
+    /// Rebuilds a tape from `Tape::cells`
+    pub fn from_cells(cells: Option<Vec<u8>>) -> Self {
+        Tape { array: cells }
+    }
+
     pub fn init(&mut self, size: usize) {
         self.array = Some(vec![0u8; size]);
     }
 
     pub fn size(&self) -> usize {
-        self.array.as_ref().unwrap().capacity()
+        self.array.as_ref().unwrap().len()
     }
 
-    #[allow(dead_code)]
-    pub fn len(&self) -> usize {
-        self.array.as_ref().unwrap().len()
+    /// The cells, or `None` before the tape is made
+    pub fn cells(&self) -> Option<&[u8]> {
+        self.array.as_deref()
+    }
+
+    pub fn cells_mut(&mut self) -> Option<&mut [u8]> {
+        self.array.as_deref_mut()
     }
 }
 
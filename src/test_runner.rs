@@ -0,0 +1,138 @@
+use crate::dump;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tape::compiler::Compiler;
+use tape::limits::Limits;
+use tape::vm::{OpCode, Program, Vm, VmState};
+
+/// Adds `path` if it is a file, or every file under it whose name ends with
+/// `suffix` if it is a directory, in order
+pub fn find_files(path: &Path, suffix: &str, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        found.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        let matches = entry
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(suffix));
+        if entry.is_dir() || matches {
+            find_files(&entry, suffix, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs every test on the VM, without input, printing whether each passed and
+/// why the others failed. Returns whether they all passed.
+pub fn run_tests(
+    tests: &[PathBuf],
+    include_dirs: &[PathBuf],
+    limits: Limits,
+    out: &mut dyn Write,
+) -> io::Result<bool> {
+    writeln!(out, "running {} tests", tests.len())?;
+    let mut failures = vec![];
+    for path in tests {
+        match run_test(path, include_dirs, limits) {
+            Ok(()) => writeln!(out, "test {} ... ok", path.display())?,
+            Err(failure) => {
+                writeln!(out, "test {} ... FAILED", path.display())?;
+                failures.push((path, failure));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        writeln!(out, "\nfailures:")?;
+    }
+    for (path, failure) in &failures {
+        writeln!(out, "\n---- {} ----\n{}", path.display(), failure)?;
+    }
+    let result = if failures.is_empty() { "ok" } else { "FAILED" };
+    writeln!(
+        out,
+        "\ntest result: {}. {} passed; {} failed",
+        result,
+        tests.len() - failures.len(),
+        failures.len()
+    )?;
+    Ok(failures.is_empty())
+}
+
+/// Runs one test, or says where and why it failed
+fn run_test(path: &Path, include_dirs: &[PathBuf], limits: Limits) -> Result<(), String> {
+    let source_code =
+        fs::read_to_string(path).map_err(|err| format!("failed reading file: {}", err))?;
+    let mut compiler = Compiler::new(&source_code);
+    compiler.path = Some(path.to_path_buf());
+    compiler.include_dirs = include_dirs.to_vec();
+    let program = compiler
+        .try_compile()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    let mut vm = Vm::with_limits(limits);
+    vm.close_input();
+    let err = match vm.run_for(&program, u64::MAX) {
+        VmState::Error(err) => err,
+        _ => return Ok(()),
+    };
+
+    let snapshot = vm.snapshot();
+    let ip = snapshot.ip;
+    let main = path.to_string_lossy();
+    let mut failure = vec![
+        format!(
+            "{}:{}: {}",
+            program.file_name(ip, &main),
+            program.lines[ip],
+            err
+        ),
+        format!("    {}", statement(&program, ip, &source_code)),
+    ];
+    match program.code[ip] {
+        OpCode::Assert(cell, _) => {
+            let index = cell
+                .idx
+                .map_or(0, |idx| snapshot.indices.get(idx).copied().unwrap_or(0));
+            let value = snapshot.tapes[cell.tape]
+                .as_ref()
+                .and_then(|cells| cells.get(index.checked_add_signed(cell.offset)?));
+            if let Some(value) = value {
+                failure.push(format!("found {}", value));
+            }
+        }
+        OpCode::ExpectOutput(id) => {
+            let captured = vm.captured().unwrap_or_default();
+            failure.push(format!(
+                "expected {:?}",
+                String::from_utf8_lossy(&program.strings[id])
+            ));
+            failure.push(format!("wrote    {:?}", String::from_utf8_lossy(captured)));
+        }
+        _ => {}
+    }
+    let output = vm.take_output();
+    if !output.is_empty() {
+        failure.push(format!("output:\n{}", String::from_utf8_lossy(&output)));
+    }
+    Err(failure.join("\n"))
+}
+
+/// The source line the opcode at `ip` was compiled from
+fn statement(program: &Program, ip: usize, source_code: &str) -> String {
+    let line = program.lines[ip];
+    match program.op_files[ip] {
+        Some(file) => {
+            let source = dump::included_source(&program.files[file]);
+            dump::source_line(&source, line).to_owned()
+        }
+        None => dump::source_line(source_code, line).to_owned(),
+    }
+}
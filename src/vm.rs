@@ -1,5 +1,17 @@
+use crate::limits::{self, Limits};
+use crate::snapshot::Snapshot;
 use crate::tape_struct::Tape;
-use std::io::Read;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// how many instructions run between two wall-clock checks
+const CLOCK_CHECK_INTERVAL: u64 = 4096;
 
 #[derive(Debug, Clone, Copy)]
 pub enum BracketKind {
@@ -7,107 +19,644 @@ pub enum BracketKind {
     Close,
 }
 
+pub type TapeId = usize;
+pub type IdxId = usize;
+/// Index into `Program::strings`
+pub type StrId = usize;
+
+/// How many index variables a program can make, one for each register the
+/// JIT keeps them in
+pub const MAX_IDXS: usize = 8;
+
+/// The furthest a cell can be from the start of its tape or from its index,
+/// so that the JIT can always address it
+pub const MAX_OFFSET: usize = i32::MAX as usize;
+
+/// The cell an opcode reads or writes: the one `offset` cells past `idx` on
+/// `tape`, or the one at `offset` when there is no `idx`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub tape: TapeId,
+    pub idx: Option<IdxId>,
+    pub offset: isize,
+}
+
+impl Cell {
+    /// The cell under `idx`
+    pub fn under(tape: TapeId, idx: IdxId) -> Self {
+        Self {
+            tape,
+            idx: Some(idx),
+            offset: 0,
+        }
+    }
+
+    /// The cell at a fixed position
+    pub fn at(tape: TapeId, position: usize) -> Self {
+        Self {
+            tape,
+            idx: None,
+            offset: position as isize,
+        }
+    }
+
+    /// The cell `offset` cells past this one, unless that ends up further
+    /// than [`MAX_OFFSET`]
+    pub fn offset_by(self, offset: isize) -> Option<Self> {
+        let offset = self.offset.checked_add(offset)?;
+        (offset.unsigned_abs() <= MAX_OFFSET).then_some(Self { offset, ..self })
+    }
+}
+
+/// The cell under the first index on the first tape
+impl Default for Cell {
+    fn default() -> Self {
+        Cell::under(0, 0)
+    }
+}
+
+impl fmt::Debug for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.idx {
+            Some(idx) if self.offset == 0 => write!(f, "tape#{}[idx#{}]", self.tape, idx),
+            Some(idx) => write!(f, "tape#{}[idx#{}{:+}]", self.tape, idx, self.offset),
+            None => write!(f, "tape#{}[{}]", self.tape, self.offset),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum OpCode {
-    #[allow(unused)]
-    MakeTape(usize),
-    IncrPtr,
-    DecrPtr,
-    IncrCell,
-    DecrCell,
-    PrintChar,
-    GetChar,
+    MakeTape(TapeId, usize),
+    IncrPtr(IdxId),
+    /// Moves an index straight to a cell position
+    SetPtr(IdxId, usize),
+    DecrPtr(IdxId),
+    /// Moves an index by a number of cells, stopping at the first one like
+    /// `DecrPtr`. Only made by `optimizer`, out of runs of moves.
+    MovePtr(IdxId, isize),
+    IncrCell(Cell, u8),
+    DecrCell(Cell, u8),
+    SetCell(Cell, u8),
+    /// Adds the second cell times the number to the first, failing on
+    /// overflow like `IncrCell`. Only made by `optimizer`, out of loops.
+    AddProduct(Cell, Cell, u8),
+    /// Subtracts the second cell times the number from the first
+    SubProduct(Cell, Cell, u8),
+    PrintChar(Cell),
+    GetChar(Cell),
+    /// Writes the character whose code point is in the cell, as UTF-8
+    PrintUtf8(Cell),
+    /// Writes the character whose code point is spread over the cell and the
+    /// two after it, lowest byte first, as UTF-8
+    PrintWideUtf8(Cell),
+    /// Writes the number in the cell as text
+    PrintNum(Cell, NumFormat),
+    /// Reads a decimal number into the cell, see `read_number`
+    GetNum(Cell),
+    Print(StrId),
     Debug,
-    Jump(usize, BracketKind),
+    Jump(usize, BracketKind, Cell),
+    /// Jumps over the body of a proc, to the opcode after its `Return`
+    SkipProc(usize),
+    /// Jumps forward by the offset if the cell is zero, to the `else` or the end of an `if`
+    BranchIfZero(usize, Cell),
+    /// Jumps forward by the offset, over the `else` of an `if`
+    Branch(usize),
+    /// Calls the proc whose body starts at this opcode index
+    Call(usize),
+    Return,
+    /// Fails unless the cell holds the value
+    Assert(Cell, u8),
+    /// Holds back what is written from here on, up to the matching `ExpectOutput`
+    CaptureOutput,
+    /// Fails unless what was written since the matching `CaptureOutput` is the string
+    ExpectOutput(StrId),
+}
+
+/// How `putnum` writes a number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumFormat {
+    Decimal,
+    Hex,
 }
 
-pub type Program = Vec<OpCode>;
+impl NumFormat {
+    pub fn format(self, value: u8) -> String {
+        match self {
+            NumFormat::Decimal => value.to_string(),
+            NumFormat::Hex => format!("{:x}", value),
+        }
+    }
+}
+
+/// Reads what `getnum` does from `next_byte`: whitespace, then the digits of a
+/// decimal number, then the byte ending it, which is dropped. The end of
+/// input may end the number too, but gives `None` if it comes first.
+pub fn read_number(mut next_byte: impl FnMut() -> Option<u8>) -> Result<Option<u8>, &'static str> {
+    let mut byte = next_byte();
+    while byte.is_some_and(|byte| byte.is_ascii_whitespace()) {
+        byte = next_byte();
+    }
+    match byte {
+        None => return Ok(None),
+        Some(byte) if !byte.is_ascii_digit() => return Err("Expected a number in the input"),
+        Some(_) => {}
+    }
+
+    let mut value: u8 = 0;
+    while let Some(digit @ b'0'..=b'9') = byte {
+        value = value
+            .checked_mul(10)
+            .and_then(|value| value.checked_add(digit - b'0'))
+            .ok_or("Number in the input does not fit in a cell")?;
+        byte = next_byte();
+    }
+    Ok(Some(value))
+}
+
+pub const END_OF_INPUT: &str = "Unexpected end of input";
+pub const ASSERTION_FAILED: &str = "Assertion failed";
+pub const UNEXPECTED_OUTPUT: &str = "Output differs from expect_output";
+
+/// What `getch` and `getnum` do to their cell once the input has ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eof {
+    /// Store 0
+    Zero,
+    /// Store 255, the byte of -1
+    MinusOne,
+    /// Leave the cell as it is
+    Unchanged,
+    /// Stop the program
+    #[default]
+    Error,
+}
+
+impl Eof {
+    pub fn apply(self, cell: &mut u8) -> Result<(), &'static str> {
+        match self {
+            Eof::Zero => *cell = 0,
+            Eof::MinusOne => *cell = u8::MAX,
+            Eof::Unchanged => {}
+            Eof::Error => return Err(END_OF_INPUT),
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Eof {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Eof::Zero),
+            "minus-one" => Ok(Eof::MinusOne),
+            "unchanged" => Ok(Eof::Unchanged),
+            "error" => Ok(Eof::Error),
+            _ => Err("expected zero, minus-one, unchanged or error"),
+        }
+    }
+}
+
+/// What a `Vm` is doing after running for a while
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    /// The instruction budget ran out; call `run_for` again to continue
+    Running,
+    /// Paused on `getch` until `push_input` provides a byte
+    NeedsInput,
+    Halted,
+    Error(&'static str),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub code: Vec<OpCode>,
+    /// Source line of every opcode in `code`
+    pub lines: Vec<u32>,
+    /// The bytes of every string literal `print` writes
+    pub strings: Vec<Vec<u8>>,
+    /// Included files, or standard library modules, some opcodes were compiled from
+    pub files: Vec<PathBuf>,
+    /// Index into `files` of every opcode in `code`, or `None` for the main file
+    pub op_files: Vec<Option<usize>>,
+    /// Name of every proc, with the index of the first opcode of its body
+    pub procs: Vec<(String, usize)>,
+}
+
+impl Program {
+    /// Name of the file the opcode at `index` was compiled from, or `main` for the main file
+    pub fn file_name<'a>(&'a self, index: usize, main: &'a str) -> Cow<'a, str> {
+        match self.op_files[index] {
+            Some(file) => self.files[file].to_string_lossy(),
+            None => Cow::Borrowed(main),
+        }
+    }
+}
 
-#[allow(dead_code)]
 pub struct Vm {
-    tape: Tape,
-    index: usize,
+    tapes: Vec<Tape>,
+    /// The value of every index variable, indexed by `IdxId`
+    indices: Vec<usize>,
+    ip: usize,
+    /// Where each `call` that has not returned yet goes back to
+    call_stack: Vec<usize>,
+    limits: Limits,
+    executed: u64,
+    elapsed: Duration,
+    output_len: usize,
+    input: VecDeque<u8>,
+    /// Whether the host has said no more input is coming
+    input_closed: bool,
+    eof: Eof,
+    output: Vec<u8>,
+    /// What each `expect_output` block being run has written so far, innermost last
+    captures: Vec<Vec<u8>>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
         Vm {
-            tape: Tape::new(),
-            index: 0,
+            tapes: vec![],
+            indices: vec![],
+            ip: 0,
+            call_stack: vec![],
+            limits,
+            executed: 0,
+            elapsed: Duration::ZERO,
+            output_len: 0,
+            input: VecDeque::new(),
+            input_closed: false,
+            eof: Eof::default(),
+            output: vec![],
+            captures: vec![],
+        }
+    }
+
+    /// Resumes a program from a snapshot taken with `Vm::snapshot`.
+    /// It must be run with the same `Program` it was taken from.
+    pub fn from_snapshot(snapshot: Snapshot, limits: Limits) -> Self {
+        Vm {
+            tapes: snapshot.tapes.into_iter().map(Tape::from_cells).collect(),
+            indices: snapshot.indices,
+            ip: snapshot.ip,
+            call_stack: snapshot.call_stack,
+            executed: snapshot.executed,
+            elapsed: snapshot.elapsed,
+            output_len: snapshot.output_len,
+            input: snapshot.input.into(),
+            input_closed: snapshot.input_closed,
+            eof: snapshot.eof,
+            captures: snapshot.captures,
+            ..Self::with_limits(limits)
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tapes: self
+                .tapes
+                .iter()
+                .map(|tape| tape.cells().map(|cells| cells.to_vec()))
+                .collect(),
+            indices: self.indices.clone(),
+            ip: self.ip,
+            call_stack: self.call_stack.clone(),
+            executed: self.executed,
+            elapsed: self.elapsed,
+            output_len: self.output_len,
+            input: self.input.iter().copied().collect(),
+            input_closed: self.input_closed,
+            eof: self.eof,
+            captures: self.captures.clone(),
         }
     }
 
-    #[allow(dead_code)]
+    /// Queues bytes to be read by `getch`
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    /// Marks the end of input: once the queued bytes run out, `getch` and
+    /// `getnum` do what `set_eof` says instead of waiting for more
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    pub fn set_eof(&mut self, eof: Eof) {
+        self.eof = eof;
+    }
+
+    /// Takes everything the program printed since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output)
+    }
+
+    /// What the innermost `expect_output` block being run has written, if any
+    pub fn captured(&self) -> Option<&[u8]> {
+        self.captures.last().map(Vec::as_slice)
+    }
+
+    /// Instructions executed so far
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn step(&mut self, program: &Program) -> VmState {
+        self.run_for(program, 1)
+    }
+
+    /// Executes at most `n` instructions, stopping early when the program
+    /// halts, fails or waits for input.
+    pub fn run_for(&mut self, program: &Program, n: u64) -> VmState {
+        let started = Instant::now();
+        let timeout = self.limits.timeout;
+        let timed_out = |elapsed: Duration| {
+            timeout.is_some_and(|timeout| elapsed + started.elapsed() >= timeout)
+        };
+        if timed_out(self.elapsed) {
+            return VmState::Error(limits::TIMED_OUT);
+        }
+
+        let mut state = VmState::Running;
+        for i in 1..=n {
+            state = self.execute(program);
+            if state != VmState::Running {
+                break;
+            }
+            if i % CLOCK_CHECK_INTERVAL == 0 && timed_out(self.elapsed) {
+                state = VmState::Error(limits::TIMED_OUT);
+                break;
+            }
+        }
+        self.elapsed += started.elapsed();
+        state
+    }
+
+    /// Runs the program to completion on stdin and stdout
     pub fn run(&mut self, program: &Program) -> Result<(), &'static str> {
+        let mut stdout = std::io::stdout().lock();
+        loop {
+            let state = self.run_for(program, CLOCK_CHECK_INTERVAL);
+            if stdout.write_all(&self.take_output()).is_err() {
+                return Err("IO error");
+            }
+
+            match state {
+                VmState::Running => {}
+                VmState::NeedsInput => {
+                    if stdout.flush().is_err() {
+                        return Err("IO error");
+                    }
+                    match std::io::stdin().lock().bytes().next() {
+                        Some(Ok(byte)) => self.push_input(&[byte]),
+                        Some(Err(_)) => return Err("IO error"),
+                        None => self.close_input(),
+                    }
+                }
+                VmState::Halted => return stdout.flush().map_err(|_| "IO error"),
+                VmState::Error(err) => {
+                    let _ = stdout.flush();
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, program: &Program) -> VmState {
+        let Some(&op) = program.code.get(self.ip) else {
+            return VmState::Halted;
+        };
+        match op {
+            _ if self.input_closed => {}
+            OpCode::GetChar(_) if self.input.is_empty() => return VmState::NeedsInput,
+            OpCode::GetNum(_) if !self.has_number() => return VmState::NeedsInput,
+            _ => {}
+        }
+
+        if self.limits.fuel == Some(self.executed) {
+            return VmState::Error(limits::FUEL_EXHAUSTED);
+        }
+        self.executed += 1;
+
+        match self.execute_op(op, program) {
+            Ok(()) => self.state_at(program),
+            Err(err) => VmState::Error(err),
+        }
+    }
+
+    fn execute_op(&mut self, op: OpCode, program: &Program) -> Result<(), &'static str> {
         use OpCode::*;
 
-        let mut iter = 0;
-        while iter < program.len() {
-            match program[iter] {
-                MakeTape(size) => {
-                    self.tape.init(size);
+        match op {
+            MakeTape(id, size) => {
+                if self.limits.max_tape.is_some_and(|max| size > max) {
+                    return Err(limits::TAPE_TOO_LARGE);
                 }
-                IncrPtr => {
-                    if self.index >= self.tape.size() {
-                        println!(
-                            "self.index out of range: `{}` is greater than `{}`",
-                            self.index,
-                            self.tape.size()
-                        );
-                        std::process::exit(1);
-                    }
-                    self.index += 1;
+                if self.tapes.len() <= id {
+                    self.tapes.resize_with(id + 1, Tape::new);
                 }
-                DecrPtr => {
-                    if self.index > 0 {
-                        self.index -= 1;
-                    }
+                self.tapes[id].init(size);
+            }
+            IncrPtr(idx) => {
+                let index = self.index_mut(idx);
+                *index = index.saturating_add(1);
+            }
+            SetPtr(idx, position) => {
+                *self.index_mut(idx) = position;
+            }
+            DecrPtr(idx) => {
+                let index = self.index_mut(idx);
+                *index = index.saturating_sub(1);
+            }
+            MovePtr(idx, n) => {
+                let index = self.index_mut(idx);
+                *index = index.saturating_add_signed(n);
+            }
+            IncrCell(cell, n) => {
+                let cell = self.cell(cell)?;
+                *cell = cell.checked_add(n).ok_or("An overflow occurred")?;
+            }
+            DecrCell(cell, n) => {
+                let cell = self.cell(cell)?;
+                *cell = cell.checked_sub(n).ok_or("An overflow occurred")?;
+            }
+            SetCell(cell, n) => {
+                *self.cell(cell)? = n;
+            }
+            AddProduct(cell, factor, n) => {
+                let product = u16::from(*self.cell(factor)?) * u16::from(n);
+                let cell = self.cell(cell)?;
+                *cell =
+                    u8::try_from(u16::from(*cell) + product).map_err(|_| "An overflow occurred")?;
+            }
+            SubProduct(cell, factor, n) => {
+                let product = u16::from(*self.cell(factor)?) * u16::from(n);
+                let cell = self.cell(cell)?;
+                *cell = u16::from(*cell)
+                    .checked_sub(product)
+                    .and_then(|value| u8::try_from(value).ok())
+                    .ok_or("An overflow occurred")?;
+            }
+            PrintChar(cell) => {
+                let value = *self.cell(cell)?;
+                self.write_output(&[value])?;
+            }
+            Print(id) => self.write_output(&program.strings[id])?,
+            PrintUtf8(cell) => {
+                let c = char::from(*self.cell(cell)?);
+                self.write_output(c.encode_utf8(&mut [0; 4]).as_bytes())?;
+            }
+            PrintWideUtf8(cell) => {
+                let mut bytes = [0; 4];
+                for (i, byte) in bytes[..3].iter_mut().enumerate() {
+                    let cell = cell.offset_by(i as isize).ok_or("Index out of range")?;
+                    *byte = *self.cell(cell)?;
                 }
-                IncrCell => {
-                    if self.tape[self.index] < u8::MAX {
-                        self.tape[self.index] += 1;
-                    } else {
-                        return Err("An overflow occurred");
+                let c = char::from_u32(u32::from_le_bytes(bytes)).ok_or("Invalid code point")?;
+                self.write_output(c.encode_utf8(&mut [0; 4]).as_bytes())?;
+            }
+            PrintNum(cell, format) => {
+                let number = format.format(*self.cell(cell)?);
+                self.write_output(number.as_bytes())?;
+            }
+            GetNum(cell) => {
+                self.cell(cell)?;
+                match read_number(|| self.input.pop_front())? {
+                    Some(value) => *self.cell(cell)? = value,
+                    None => self.eof.apply(self.cell(cell)?)?,
+                }
+            }
+            GetChar(cell) => {
+                self.cell(cell)?;
+                match self.input.pop_front() {
+                    Some(byte) => *self.cell(cell)? = byte,
+                    None => self.eof.apply(self.cell(cell)?)?,
+                }
+            }
+            Debug => {
+                // counted against `max_output` like everything else printed
+                let mut dump = vec![];
+                for tape in self.tapes.iter().filter(|tape| tape.cells().is_some()) {
+                    let _ = writeln!(dump, "{:#?}", tape);
+                }
+                self.write_output(&dump)?;
+            }
+            Jump(offset, kind, cell) => match kind {
+                BracketKind::Open => {
+                    // lands right after the loop's opening jump, whose
+                    // condition is known to hold
+                    if *self.cell(cell)? != 0 {
+                        self.ip -= offset;
                     }
                 }
-                DecrCell => {
-                    if self.tape[self.index] > 0 {
-                        self.tape[self.index] -= 1;
-                    } else {
-                        return Err("An overflow occurred");
+                BracketKind::Close => {
+                    if *self.cell(cell)? == 0 {
+                        self.ip += offset;
+                        return Ok(());
                     }
                 }
-                PrintChar => print!("{}", self.tape[self.index] as char),
-                GetChar => {
-                    self.tape[self.index] = std::io::stdin()
-                        .bytes()
-                        .next()
-                        .and_then(|res| res.ok())
-                        .unwrap();
-                    //    let mut buf = [0; 2];    // 2 because of the "\n" char
-                    //    std::io::stdin().read(&mut buf).unwrap();
-                    //    self.tape[self.index] = buf[0];
+            },
+            SkipProc(offset) | Branch(offset) => {
+                self.ip += offset;
+                return Ok(());
+            }
+            BranchIfZero(offset, cell) => {
+                if *self.cell(cell)? == 0 {
+                    self.ip += offset;
+                    return Ok(());
                 }
-                Debug => {
-                    println!("{:#?}", self.tape);
+            }
+            Call(target) => {
+                if self.call_stack.len() >= self.limits.max_call_depth {
+                    return Err(limits::CALL_TOO_DEEP);
                 }
-                Jump(ref offset, kind) => match kind {
-                    BracketKind::Open => {
-                        if self.tape[self.index] != 0 {
-                            iter -= *offset;
-                            continue;
-                        }
-                    }
-                    BracketKind::Close => {
-                        if self.tape[self.index] == 0 {
-                            iter += *offset;
-                            continue;
-                        }
-                    }
-                },
+                self.call_stack.push(self.ip + 1);
+                self.ip = target;
+                return Ok(());
+            }
+            Return => {
+                self.ip = self.call_stack.pop().ok_or("Return outside of a proc")?;
+                return Ok(());
             }
-            iter += 1;
+            Assert(cell, n) => {
+                if *self.cell(cell)? != n {
+                    return Err(ASSERTION_FAILED);
+                }
+            }
+            CaptureOutput => self.captures.push(vec![]),
+            ExpectOutput(id) => {
+                // a failed block keeps its output, for `captured`
+                if self.captured() != Some(program.strings[id].as_slice()) {
+                    return Err(UNEXPECTED_OUTPUT);
+                }
+                self.captures.pop();
+            }
+        }
+        self.ip += 1;
+        Ok(())
+    }
+
+    fn index_mut(&mut self, idx: IdxId) -> &mut usize {
+        if self.indices.len() <= idx {
+            self.indices.resize(idx + 1, 0);
+        }
+        &mut self.indices[idx]
+    }
+
+    fn cell(&mut self, cell: Cell) -> Result<&mut u8, &'static str> {
+        let base = match cell.idx {
+            Some(idx) => self.indices.get(idx).copied().unwrap_or(0),
+            None => 0,
+        };
+        // a tape that has not been made has no cells
+        let cells = self.tapes.get_mut(cell.tape).and_then(Tape::cells_mut);
+        let cell = cells.zip(base.checked_add_signed(cell.offset));
+        cell.and_then(|(cells, index)| cells.get_mut(index))
+            .ok_or("Index out of range")
+    }
+
+    /// Writes as much of `bytes` as `max_output` leaves room for
+    fn write_output(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let room = self
+            .limits
+            .max_output
+            .map_or(usize::MAX, |max| max.saturating_sub(self.output_len));
+        let len = bytes.len().min(room);
+        self.output_len += len;
+        let output = self.captures.last_mut().unwrap_or(&mut self.output);
+        output.extend_from_slice(&bytes[..len]);
+        if len < bytes.len() {
+            return Err(limits::OUTPUT_TOO_LARGE);
         }
         Ok(())
     }
+
+    /// Whether the queued input holds all `read_number` will read, up to the
+    /// byte ending the number
+    fn has_number(&self) -> bool {
+        self.input
+            .iter()
+            .skip_while(|byte| byte.is_ascii_whitespace())
+            .any(|byte| !byte.is_ascii_digit())
+    }
+
+    fn state_at(&self, program: &Program) -> VmState {
+        if self.ip < program.code.len() {
+            VmState::Running
+        } else {
+            VmState::Halted
+        }
+    }
 }
@@ -0,0 +1,154 @@
+use crate::vm::{Program, Vm, VmState};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+impl Vm {
+    /// Runs the program to completion without blocking: `getch` awaits
+    /// `input`, printed bytes are awaited into `output`, and control goes back
+    /// to the executor every `yield_every` instructions so many programs can
+    /// share one thread.
+    pub async fn run_async<R, W>(
+        &mut self,
+        program: &Program,
+        mut input: R,
+        mut output: W,
+        yield_every: u64,
+    ) -> Result<(), &'static str>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        loop {
+            let state = self.run_for(program, yield_every.max(1));
+            write_all(&mut output, &self.take_output()).await?;
+
+            match state {
+                VmState::Running => YieldNow(false).await,
+                VmState::NeedsInput => {
+                    flush(&mut output).await?;
+                    match read_byte(&mut input).await? {
+                        Some(byte) => self.push_input(&[byte]),
+                        None => self.close_input(),
+                    }
+                }
+                VmState::Halted => return flush(&mut output).await,
+                VmState::Error(err) => {
+                    let _ = flush(&mut output).await;
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(
+    output: &mut W,
+    mut buf: &[u8],
+) -> Result<(), &'static str> {
+    while !buf.is_empty() {
+        match poll_fn(|cx| Pin::new(&mut *output).poll_write(cx, buf)).await {
+            Ok(0) | Err(_) => return Err("IO error"),
+            Ok(n) => buf = &buf[n..],
+        }
+    }
+    Ok(())
+}
+
+async fn flush<W: AsyncWrite + Unpin>(output: &mut W) -> Result<(), &'static str> {
+    poll_fn(|cx| Pin::new(&mut *output).poll_flush(cx))
+        .await
+        .map_err(|_| "IO error")
+}
+
+/// Reads one byte, or `None` at end of input
+async fn read_byte<R: AsyncRead + Unpin>(input: &mut R) -> Result<Option<u8>, &'static str> {
+    let mut buf = [0];
+    match poll_fn(|cx| Pin::new(&mut *input).poll_read(cx, &mut buf)).await {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(buf[0])),
+        Err(_) => Err("IO error"),
+    }
+}
+
+/// Returns `Pending` once, so the executor gets to run other tasks
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+struct Channel {
+    bytes: VecDeque<u8>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// Creates an input source for `Vm::run_async` fed from elsewhere in the
+/// host, e.g. from messages arriving on a socket. Dropping the sender is the
+/// end of input.
+pub fn input_channel() -> (InputSender, ChannelInput) {
+    let channel = Arc::new(Mutex::new(Channel::default()));
+    (InputSender(channel.clone()), ChannelInput(channel))
+}
+
+pub struct InputSender(Arc<Mutex<Channel>>);
+
+impl InputSender {
+    pub fn send(&self, bytes: &[u8]) {
+        let mut channel = self.0.lock().unwrap();
+        channel.bytes.extend(bytes);
+        if let Some(waker) = channel.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for InputSender {
+    fn drop(&mut self) {
+        let mut channel = self.0.lock().unwrap();
+        channel.closed = true;
+        if let Some(waker) = channel.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+pub struct ChannelInput(Arc<Mutex<Channel>>);
+
+impl AsyncRead for ChannelInput {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut channel = self.0.lock().unwrap();
+        if channel.bytes.is_empty() {
+            if channel.closed {
+                return Poll::Ready(Ok(0));
+            }
+            channel.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(channel.bytes.len());
+        for (slot, byte) in buf.iter_mut().zip(channel.bytes.drain(..n)) {
+            *slot = byte;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
@@ -10,20 +10,33 @@ endif
 syn match identifier '\k\+'
 syn match repeatStmt '[+]\d\+'
 syn match number '\d\+'
+syn match charLiteral "'\\\?.'"
 syn keyword makeKeyword make nextgroup=identifier skipwhite
-syn keyword tapeKeywords incr decr putch getch debug nextgroup=identifier skipwhite
+syn keyword tapeKeywords incr decr set putch putnum putu print getch getnum debug assert expect_output nextgroup=identifier skipwhite
+syn keyword tapeKeywords by hex wide
+syn keyword makeKeyword proc call macro include nextgroup=identifier skipwhite
 syn keyword tapeKeywords loop nextgroup=loopBlock skipwhite
+syn keyword tapeKeywords if else
+syn keyword tapeKeywords goto nextgroup=identifier skipwhite
+syn keyword makeKeyword alias nextgroup=identifier skipwhite
 "syn region loopBlock start='(' end=')' fold transparent contains=keyword,identifier,repeatStmt
 "syn region tapeAccess start='\[' end=']' fold transparent contains=identifier,number
+syn region stringLiteral start='"' skip='\\.' end='"'
 syn region commentRegion start='#!' end='\n' fold
+syn region docComment start='#!!' end='\n'
+syn region blockComment start='#{' end='}#' contains=blockComment fold
 
 let b:current_syntax = "tape"
 
 hi def link identifier      Identifier
 hi def link repeatStmt      Constant
+hi def link charLiteral     Character
+hi def link stringLiteral   String
 hi def link tapeKeywords    Statement
 hi def link makeKeyword     Statement
 hi def link commentRegion   Comment
+hi def link blockComment    Comment
+hi def link docComment      SpecialComment
 "hi def link loopBlock     PreProc
 "hi def link tapeAccess    PreProc
 
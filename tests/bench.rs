@@ -0,0 +1,79 @@
+//! Runs `tape bench` on small programs, checking what it reports rather than
+//! how fast they are.
+
+use std::process::{Command, Output};
+
+fn tape_bench(program: &str, opt_levels: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tape"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["bench", "--runs", "3", "--warmup", "0", program])
+        .args(opt_levels)
+        .output()
+        .unwrap()
+}
+
+/// The instructions reported for each backend and level
+fn instructions(stdout: &str) -> Vec<(String, u64)> {
+    stdout
+        .lines()
+        .skip(2)
+        .map(|line| {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            let row = format!("{} {}", columns[0], columns[1]);
+            (row, columns[columns.len() - 3].parse().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn reports_every_backend() {
+    let output = tape_bench("tests/programs/control/nested_loops.tp", &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.starts_with("tests/programs/control/nested_loops.tp: 3 runs after 0 warmup\n"));
+
+    let instructions = instructions(&stdout);
+    assert_eq!(
+        instructions
+            .iter()
+            .map(|(row, _)| row.as_str())
+            .collect::<Vec<_>>(),
+        ["vm -O0", "jit -O0"]
+    );
+    assert!(instructions[0].1 > 0);
+    assert_eq!(instructions[0].1, instructions[1].1, "{}", stdout);
+}
+
+#[test]
+fn reports_every_level() {
+    let output = tape_bench("tests/programs/optimizer/idioms.tp", &["-O0", "-O3"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+
+    let instructions = instructions(&stdout);
+    assert_eq!(
+        instructions
+            .iter()
+            .map(|(row, _)| row.as_str())
+            .collect::<Vec<_>>(),
+        ["vm -O0", "jit -O0", "vm -O3", "jit -O3"]
+    );
+    assert!(instructions[2].1 < instructions[0].1, "{}", stdout);
+}
+
+#[test]
+fn reports_failing_programs() {
+    let output = tape_bench("tests/programs/errors/overflow.tp", &[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(
+        stdout.contains("vm -O0   failed: An overflow occurred"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("jit -O0  failed: An overflow occurred"),
+        "{}",
+        stdout
+    );
+}
@@ -0,0 +1,71 @@
+//! Calls nest as deep as `max_call_depth` allows on both backends, even past
+//! what the machine stack would hold for the JIT, and fail the same way beyond.
+
+mod common;
+
+use common::{compile, run_on_jit, run_vm};
+use tape::limits::{self, Limits};
+use tape::vm::VmState;
+
+/// Calls itself 256 * 255 times, then prints the tapes and a number
+const DEEP: &str = "make tape[3]
+make p: idx
+proc r (
+    if tape[p] (
+        decr tape[p]
+        call r
+    ) else (
+        if tape[p+1] (
+            decr tape[p+1]
+            set tape[p] 255
+            call r
+        ) else (
+            debug
+            putnum tape[p+2]
+        )
+    )
+)
+set tape[p+1] 255
+set tape[p+2] 42
+call r
+";
+
+const ENDLESS: &str = "make tape[1]
+proc r (
+    call r
+)
+call r
+";
+
+fn limits(max_call_depth: usize) -> Limits {
+    Limits {
+        max_call_depth,
+        ..Limits::default()
+    }
+}
+
+#[test]
+fn nests_as_deep_as_allowed() {
+    let program = compile(DEEP);
+    let (state, expected, _) = run_vm(&program, limits(70_000));
+    assert_eq!(state, VmState::Halted);
+    assert!(expected.ends_with(b"42"));
+    let (result, output, _) = run_on_jit(&program, limits(70_000));
+    assert_eq!((result, output), (Ok(()), expected));
+
+    let (state, _, _) = run_vm(&program, limits(60_000));
+    assert_eq!(state, VmState::Error(limits::CALL_TOO_DEEP));
+    let (result, _, _) = run_on_jit(&program, limits(60_000));
+    assert_eq!(result, Err(limits::CALL_TOO_DEEP));
+}
+
+#[test]
+fn fails_past_max_call_depth() {
+    let program = compile(ENDLESS);
+    for max_call_depth in [0, 1, 1_000_000] {
+        let (state, _, _) = run_vm(&program, limits(max_call_depth));
+        assert_eq!(state, VmState::Error(limits::CALL_TOO_DEEP));
+        let (result, _, _) = run_on_jit(&program, limits(max_call_depth));
+        assert_eq!(result, Err(limits::CALL_TOO_DEEP), "{}", max_call_depth);
+    }
+}
@@ -0,0 +1,33 @@
+//! Helpers for the tests running programs on the VM and the JIT.
+
+// each test crate uses only some of them
+#![allow(dead_code)]
+
+use tape::compiler::Compiler;
+use tape::jit_compiler::{jit_compile, run_jit, JitState};
+use tape::limits::Limits;
+use tape::vm::{Program, Vm, VmState};
+
+pub fn compile(source: &str) -> Program {
+    Compiler::new(source).try_compile().unwrap()
+}
+
+/// How the VM ends with `limits`, what it printed and how many opcodes it
+/// executed
+pub fn run_vm(program: &Program, limits: Limits) -> (VmState, Vec<u8>, u64) {
+    let mut vm = Vm::with_limits(limits);
+    let state = vm.run_for(program, u64::MAX);
+    (state, vm.take_output(), vm.executed())
+}
+
+/// The same as `run_vm` on the JIT
+pub fn run_on_jit(program: &Program, limits: Limits) -> (Result<(), &'static str>, Vec<u8>, u64) {
+    let jit_code = jit_compile(program).unwrap();
+    let mut output = vec![];
+    let mut state = JitState::new(Box::new(&b""[..]), Box::new(&mut output));
+    state.limits = limits;
+    let result = run_jit(&mut state, &jit_code);
+    let executed = state.executed();
+    drop(state);
+    (result, output, executed)
+}
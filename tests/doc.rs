@@ -0,0 +1,39 @@
+//! Runs `tape doc`, which turns the `#!!` comments of a module into Markdown.
+
+use std::process::Command;
+
+#[test]
+fn documents_the_module_and_its_definitions() {
+    let output = Command::new(env!("CARGO_BIN_EXE_tape"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["doc", "tests/doc/counter.tp"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "\
+# tests/doc/counter.tp
+
+Counts things on a tape of its own.
+Every macro takes the index to count at.
+
+## `make counts[8]`
+
+The counts, one per cell
+
+## `make at: idx`
+
+## `make scratch[1]`
+
+## `macro bump(i)`
+
+Adds one to the count under `i`
+
+## `proc show`
+
+Prints every count
+on one line
+"
+    );
+}
@@ -0,0 +1,44 @@
+//! Runs `tape dump --ir`, whose listing shows where every jump lands.
+
+use std::process::Command;
+
+#[test]
+fn lists_loops_ifs_and_procs() {
+    let output = Command::new(env!("CARGO_BIN_EXE_tape"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["dump", "--ir", "tests/dump/control.tp"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "\
+; line 1: make tape[2]
+0000  MakeTape(0, 2)
+; line 3: proc twice (
+0001  SkipProc(3) -> 0004
+; line 4: incr tape[p] by 2
+0002  IncrCell(tape#0[idx#0], 2)
+; line 5: )
+0003  Return
+; line 6: set tape[p+1] 3
+0004  SetCell(tape#0[idx#0+1], 3)
+; line 7: loop tape[p+1] (
+0005  Jump(4, Close, tape#0[idx#0+1]) -> 0009
+; line 8: call twice
+0006    Call(2) -> 0002
+; line 9: decr tape[p+1]
+0007    DecrCell(tape#0[idx#0+1], 1)
+; line 10: )
+0008  Jump(3, Open, tape#0[idx#0+1]) -> 0006
+; line 11: if tape[p] (
+0009  BranchIfZero(3, tape#0[idx#0]) -> 0012
+; line 12: putnum tape[p]
+0010  PrintNum(tape#0[idx#0], Decimal)
+; line 13: ) else (
+0011  Branch(2) -> 0013
+; line 14: print \"none\"
+0012  Print(0) \"none\"
+"
+    );
+}
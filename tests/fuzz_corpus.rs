@@ -0,0 +1,47 @@
+//! Replays the fuzz corpus, and the example programs, through the fuzz targets,
+//! so they run offline on every `cargo test` without libFuzzer.
+
+#[path = "../fuzz/src/lib.rs"]
+mod targets;
+
+use std::fs;
+use std::path::Path;
+
+/// Feeds every file in the corpus of `target`, and the bundled programs, to `run`
+fn replay(target: &str, run: fn(&[u8])) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dirs = [
+        (root.join("fuzz/corpus").join(target), false),
+        (root.join("examples"), true),
+        (root.join("std"), true),
+    ];
+    let mut count = 0;
+    for (dir, sources_only) in dirs {
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            // examples come with the input and output of their golden tests
+            if sources_only && path.extension().is_none_or(|ext| ext != "tp") {
+                continue;
+            }
+            println!("{}", path.display());
+            run(&fs::read(&path).unwrap());
+            count += 1;
+        }
+    }
+    assert!(count > 0, "no corpus for {}", target);
+}
+
+#[test]
+fn scanner() {
+    replay("scanner", targets::scanner);
+}
+
+#[test]
+fn compiler() {
+    replay("compiler", targets::compiler);
+}
+
+#[test]
+fn differential() {
+    replay("differential", targets::differential);
+}
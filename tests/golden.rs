@@ -0,0 +1,175 @@
+//! Runs every program in `examples/` and `tests/programs/` on both the VM and
+//! the JIT, at every optimization level, feeding `name.input`, if there is one,
+//! to `getch`. Every run must print `name.expected`, and fail the same way if
+//! they fail, with the error in `name.error`.
+//!
+//! `cargo test --test golden -- --bless` writes the `.expected` and `.error`
+//! files from what the programs print instead, and any other argument only runs the programs
+//! whose path contains it.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+
+/// Seconds a program may run, so one that never ends fails instead of hanging
+const TIMEOUT: &str = "10";
+
+/// The optimization levels `-O` takes
+const OPT_LEVELS: [u8; 4] = [0, 1, 2, 3];
+
+/// Every `.tp` file under `dir`, sorted
+fn programs(dir: &Path, found: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            programs(&path, found);
+        } else if path.extension().is_some_and(|ext| ext == "tp") {
+            found.push(path);
+        }
+    }
+}
+
+fn run(program: &Path, input: &[u8], jit: bool, opt_level: u8) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_tape"));
+    if jit {
+        command.arg("--jit");
+    }
+    let mut child = command
+        .arg(format!("-O{}", opt_level))
+        .arg("--timeout")
+        .arg(TIMEOUT)
+        .arg(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // a program may end without reading all of its input
+    let _ = child.stdin.take().unwrap().write_all(input);
+    child.wait_with_output().unwrap()
+}
+
+/// How a run ended, for telling apart the VM's from the JIT's
+fn ending(output: &Output) -> String {
+    match output.status.code() {
+        Some(0) => "succeeds".to_owned(),
+        _ => format!(
+            "fails with `{}`",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+/// Runs `program` on both backends at every level, and checks or, when
+/// blessing, writes what it prints
+fn check(program: &Path, bless: bool) -> Result<(), String> {
+    let input = fs::read(program.with_extension("input")).unwrap_or_default();
+    let vm = run(program, &input, false, 0);
+    for opt_level in OPT_LEVELS {
+        for (backend, jit) in [("VM", false), ("JIT", true)] {
+            if opt_level == 0 && !jit {
+                continue;
+            }
+            let other = run(program, &input, jit, opt_level);
+            if ending(&vm) != ending(&other) {
+                return Err(format!(
+                    "the VM {} but the {} at -O{} {}",
+                    ending(&vm),
+                    backend,
+                    opt_level,
+                    ending(&other)
+                ));
+            }
+            if vm.stdout != other.stdout {
+                return Err(format!(
+                    "the VM prints\n{}\nbut the {} at -O{} prints\n{}",
+                    String::from_utf8_lossy(&vm.stdout),
+                    backend,
+                    opt_level,
+                    String::from_utf8_lossy(&other.stdout)
+                ));
+            }
+        }
+    }
+
+    let error = (!vm.status.success()).then_some(vm.stderr.as_slice());
+    compare(&program.with_extension("expected"), Some(&vm.stdout), bless)?;
+    compare(&program.with_extension("error"), error, bless)
+}
+
+/// Checks that the file at `path` holds `actual`, or that there is no such
+/// file if `actual` is `None`, or when blessing, makes it so
+fn compare(path: &Path, actual: Option<&[u8]>, bless: bool) -> Result<(), String> {
+    let name = path.file_name().unwrap().to_string_lossy();
+    if bless {
+        return match actual {
+            Some(bytes) => fs::write(path, bytes),
+            None if path.exists() => fs::remove_file(path),
+            None => Ok(()),
+        }
+        .map_err(|err| err.to_string());
+    }
+    match (fs::read(path).ok(), actual) {
+        (Some(bytes), Some(actual)) if bytes == actual => Ok(()),
+        (None, None) => Ok(()),
+        (Some(bytes), Some(actual)) => Err(format!(
+            "it prints\n{}\nbut {} expects\n{}",
+            String::from_utf8_lossy(actual),
+            name,
+            String::from_utf8_lossy(&bytes)
+        )),
+        (Some(_), None) => Err(format!("it succeeds but there is a {}", name)),
+        (None, Some(_)) => Err(format!("there is no {}, --bless writes it", name)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let bless = args.iter().any(|arg| arg == "--bless");
+    // libtest's own flags, like `--nocapture`, mean nothing here
+    let filters: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut found = vec![];
+    programs(&root.join("examples"), &mut found);
+    programs(&root.join("tests/programs"), &mut found);
+
+    let mut failures = vec![];
+    let mut passed = 0;
+    for program in found {
+        let name = program.strip_prefix(root).unwrap().display().to_string();
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter.as_str())) {
+            continue;
+        }
+        match check(&program, bless) {
+            Ok(()) => {
+                println!("test {} ... ok", name);
+                passed += 1;
+            }
+            Err(err) => {
+                println!("test {} ... FAILED", name);
+                failures.push((name, err));
+            }
+        }
+    }
+
+    for (name, err) in &failures {
+        println!("\n---- {} ----\n{}", name, err);
+    }
+    let result = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed\n",
+        result,
+        passed,
+        failures.len()
+    );
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,51 @@
+//! The JIT counts executed opcodes like the VM, even when fuel runs out.
+
+mod common;
+
+use common::{compile, run_on_jit, run_vm};
+use tape::limits::{self, Limits};
+use tape::vm::VmState;
+
+const COUNTDOWN: &str = "make tape[2]
+make p: idx
+set tape[p] 200
+loop tape[p] (
+    decr tape[p]
+    putnum tape[p]
+)
+";
+
+fn limits(fuel: Option<u64>) -> Limits {
+    Limits {
+        fuel,
+        ..Limits::default()
+    }
+}
+
+#[test]
+fn counts_all_of_the_fuel_when_it_runs_out() {
+    let program = compile(COUNTDOWN);
+    for fuel in [1, 2, 5, 100, 599] {
+        let (state, _, executed) = run_vm(&program, limits(Some(fuel)));
+        assert_eq!(state, VmState::Error(limits::FUEL_EXHAUSTED));
+        assert_eq!(executed, fuel);
+        let (result, _, executed) = run_on_jit(&program, limits(Some(fuel)));
+        assert_eq!(
+            (result, executed),
+            (Err(limits::FUEL_EXHAUSTED), fuel),
+            "{}",
+            fuel
+        );
+    }
+}
+
+#[test]
+fn counts_like_the_vm_otherwise() {
+    let program = compile(COUNTDOWN);
+    let (state, _, executed) = run_vm(&program, limits(None));
+    assert_eq!(state, VmState::Halted);
+    for fuel in [None, Some(executed)] {
+        let (result, _, jit_executed) = run_on_jit(&program, limits(fuel));
+        assert_eq!((result, jit_executed), (Ok(()), executed));
+    }
+}
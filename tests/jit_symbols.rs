@@ -0,0 +1,106 @@
+//! Names the JIT gives its code for `perf` and `gdb`, and the perf map they go to.
+
+use std::fs;
+use tape::compiler::Compiler;
+use tape::jit_compiler::{jit_compile, write_perf_map, JitCode, Symbol};
+use tape::vm::Program;
+
+const SOURCE: &str = "make tape[4]
+make p: idx
+proc clear (
+    loop tape[p+1] (
+        decr tape[p+1]
+    )
+)
+set tape[p+1] 3
+call clear
+putnum tape[p+1]
+";
+
+fn compile() -> (Program, JitCode) {
+    let program = Compiler::new(SOURCE).try_compile().unwrap();
+    let jit_code = jit_compile(&program).unwrap();
+    (program, jit_code)
+}
+
+#[test]
+fn names_lines_loops_and_procs() {
+    let (program, jit_code) = compile();
+    let symbols = jit_code.symbols(&program, "clear.tp");
+    let names: Vec<&str> = symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "tape:prologue",
+            "tape:clear.tp:1",
+            "tape:clear.tp:3",
+            "tape:clear:clear.tp:4:loop",
+            "tape:clear:clear.tp:5",
+            "tape:clear:clear.tp:6",
+            "tape:clear:clear.tp:7",
+            "tape:clear.tp:8",
+            "tape:clear.tp:9",
+            "tape:clear.tp:10",
+            "tape:epilogue",
+        ]
+    );
+}
+
+#[test]
+fn covers_the_code_of_every_opcode() {
+    let (program, jit_code) = compile();
+    let symbols = jit_code.symbols(&program, "clear.tp");
+    for pair in symbols.windows(2) {
+        assert_eq!(pair[0].addr + pair[0].size, pair[1].addr, "{:?}", pair);
+    }
+
+    let epilogue = symbols.last().unwrap();
+    let base = epilogue.addr - jit_code.epilogue_offset();
+    for (i, &offset) in jit_code.op_offsets().iter().enumerate() {
+        let symbol = symbols
+            .iter()
+            .find(|symbol| (symbol.addr..symbol.addr + symbol.size).contains(&(base + offset)));
+        // an opcode compiling to no code at all shares its address with the next one
+        let Some(symbol) = symbol else { continue };
+        let line = format!(":{}", program.lines[i]);
+        assert!(
+            symbol.name.ends_with(&line) || symbol.name.ends_with(&format!("{}:loop", line)),
+            "opcode {} from line {} is in {}",
+            i,
+            program.lines[i],
+            symbol.name
+        );
+    }
+}
+
+#[test]
+fn writes_perf_map_lines() {
+    let (program, jit_code) = compile();
+    let symbols = jit_code.symbols(&program, "clear.tp");
+    let path = format!("/tmp/perf-{}.map", std::process::id());
+    let _ = fs::remove_file(&path);
+    write_perf_map(&symbols).unwrap();
+    let map = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let parsed: Vec<Symbol> = map
+        .lines()
+        .map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let mut hex = || usize::from_str_radix(fields.next().unwrap(), 16).unwrap();
+            let (addr, size) = (hex(), hex());
+            Symbol {
+                addr,
+                size,
+                name: fields.next().unwrap().to_owned(),
+            }
+        })
+        .collect();
+    assert_eq!(parsed.len(), symbols.len());
+    for (parsed, symbol) in parsed.iter().zip(&symbols) {
+        assert_eq!(
+            (parsed.addr, parsed.size, &parsed.name),
+            (symbol.addr, symbol.size, &symbol.name)
+        );
+    }
+}
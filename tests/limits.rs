@@ -0,0 +1,36 @@
+//! Everything a program prints counts against `max_output`, on both backends.
+
+mod common;
+
+use common::{compile, run_on_jit, run_vm};
+use tape::limits::{self, Limits};
+use tape::vm::VmState;
+
+/// Dumps a big tape over and over
+const DEBUG_LOOP: &str = "make tape[3000]
+incr tape[idx]
+loop (
+    debug
+)
+";
+
+fn limits() -> Limits {
+    Limits {
+        max_output: Some(100),
+        fuel: Some(50),
+        ..Limits::default()
+    }
+}
+
+#[test]
+fn caps_what_debug_prints() {
+    let program = compile(DEBUG_LOOP);
+    let (state, expected, _) = run_vm(&program, limits());
+    assert_eq!(state, VmState::Error(limits::OUTPUT_TOO_LARGE));
+    assert_eq!(expected.len(), 100);
+    assert!(expected.starts_with(b"tape {"));
+
+    let (result, output, _) = run_on_jit(&program, limits());
+    assert_eq!(result, Err(limits::OUTPUT_TOO_LARGE));
+    assert_eq!(output, expected);
+}
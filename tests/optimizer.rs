@@ -0,0 +1,35 @@
+//! Rewrites `-O3` has to leave alone to keep a program running as written.
+
+use tape::compiler::Compiler;
+use tape::optimizer::optimize;
+use tape::vm::{OpCode, Vm, VmState};
+
+#[test]
+fn moves_indices_before_offsets_that_would_overflow() {
+    let mut program = Compiler::new("make tape[10]\nincr idx\nincr tape[idx+5]\n")
+        .try_compile()
+        .unwrap();
+    // past what the compiler accepts, as a program may be built by hand
+    for op in &mut program.code {
+        if let OpCode::IncrCell(cell, _) = op {
+            cell.offset = isize::MAX;
+        }
+    }
+
+    let optimized = optimize(&program, 3);
+    let incr = optimized
+        .code
+        .iter()
+        .position(|op| matches!(op, OpCode::IncrCell(..)))
+        .unwrap();
+    assert!(matches!(optimized.code[incr - 1], OpCode::IncrPtr(0)));
+    assert!(matches!(optimized.code[incr], OpCode::IncrCell(cell, 1) if cell.offset == isize::MAX));
+
+    for program in [program, optimized] {
+        let mut vm = Vm::new();
+        assert_eq!(
+            vm.run_for(&program, u64::MAX),
+            VmState::Error("Index out of range")
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! The scanner must turn any input into tokens ending in EOF, without panicking.
+
+use tape::scanner::{Scanner, TokenType};
+
+/// Pieces random sources are made of: keywords, their prefixes, and
+/// everything that starts or ends a comment, literal or symbol
+const PIECES: &[&str] = &[
+    "make",
+    "mak",
+    "ma",
+    "m",
+    "incr",
+    "inc",
+    "include",
+    "in",
+    "i",
+    "if",
+    "decr",
+    "debug",
+    "de",
+    "d",
+    "e",
+    "else",
+    "getch",
+    "getnum",
+    "get",
+    "g",
+    "goto",
+    "putch",
+    "putnum",
+    "putu",
+    "pu",
+    "p",
+    "print",
+    "proc",
+    "pr",
+    "loop",
+    "l",
+    "set",
+    "s",
+    "by",
+    "b",
+    "call",
+    "c",
+    "macro",
+    "alias",
+    "assert",
+    "a",
+    "expect_output",
+    "expect",
+    "==",
+    "x",
+    "_",
+    "é",
+    "日本",
+    "0",
+    "42",
+    "300",
+    " ",
+    "\t",
+    "\r",
+    "\n",
+    "#",
+    "!",
+    "#!",
+    "#!!",
+    "#{",
+    "}#",
+    "{",
+    "}",
+    "'",
+    "\\",
+    "\"",
+    "+",
+    "-",
+    "=",
+    ":",
+    ",",
+    "[",
+    "]",
+    "(",
+    ")",
+    "\0",
+    "€",
+];
+
+/// Scans `source` to its end, checking every token spans whole characters of it
+fn scan_all(source: &str) {
+    let mut scanner = Scanner::new(source);
+    // every token but EOF takes at least one character
+    for _ in 0..=source.chars().count() {
+        let token = scanner.scan_token();
+        assert!(
+            source.get(token.start..token.start + token.len).is_some(),
+            "token {:?} outside of {:?}",
+            token,
+            source
+        );
+        if token.typ == TokenType::EOF {
+            return;
+        }
+    }
+    panic!("no EOF scanning {:?}", source);
+}
+
+#[test]
+fn keywords_cut_short_at_the_end() {
+    for piece in PIECES {
+        for (end, _) in piece.char_indices().chain([(piece.len(), ' ')]) {
+            scan_all(&piece[..end]);
+        }
+    }
+}
+
+#[test]
+fn every_short_source() {
+    let alphabet = [
+        "a", "d", "e", "i", "p", "#", "!", "{", "}", "'", "\"", "\\", "\n", "1", "é", "\0",
+    ];
+    let mut sources = vec![String::new()];
+    for _ in 0..4 {
+        sources = sources
+            .iter()
+            .flat_map(|source| alphabet.iter().map(move |c| format!("{}{}", source, c)))
+            .collect();
+        for source in &sources {
+            scan_all(source);
+        }
+    }
+}
+
+#[test]
+fn random_sources() {
+    // xorshift, so failures reproduce
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize
+    };
+
+    for _ in 0..20_000 {
+        let len = next() % 40;
+        let source: String = (0..len).map(|_| PIECES[next() % PIECES.len()]).collect();
+        scan_all(&source);
+    }
+}
+
+#[test]
+fn keywords_need_the_whole_word() {
+    for (source, typ) in [
+        ("de", TokenType::Ident),
+        ("deb", TokenType::Ident),
+        ("debugs", TokenType::Ident),
+        ("debug", TokenType::Debug),
+        ("putu", TokenType::Putu),
+        ("put", TokenType::Ident),
+        ("ifs", TokenType::Ident),
+        ("if", TokenType::If),
+        ("posição", TokenType::Ident),
+        ("expect_output", TokenType::ExpectOutput),
+        ("expect", TokenType::Ident),
+        ("==", TokenType::EqualEqual),
+        ("=", TokenType::Equal),
+    ] {
+        assert_eq!(Scanner::new(source).scan_token().typ, typ, "{:?}", source);
+    }
+}
+
+#[test]
+fn errors_point_at_where_they_start() {
+    let mut scanner = Scanner::new("make\n\"never\nclosed");
+    scanner.scan_token();
+    let token = scanner.scan_token();
+    assert_eq!(token.typ, TokenType::Error);
+    assert_eq!(token.lexeme, "Unterminated string");
+    assert_eq!((token.start, token.len, token.line), (5, 13, 2));
+}
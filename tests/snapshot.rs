@@ -0,0 +1,220 @@
+//! Pausing a `Vm` with `step` and `run_for`, saving it with `Snapshot` and
+//! resuming it must run a program as if it never stopped.
+
+mod common;
+
+use common::compile;
+use std::time::Duration;
+use tape::limits::{self, Limits};
+use tape::snapshot::Snapshot;
+use tape::vm::{Eof, Vm, VmState};
+
+const SOURCE: &str = "make tape[4]
+make p: idx
+make out[2]
+proc shout (
+    putch tape[p+1]
+    putch tape[p+1]
+)
+set tape[p] 5
+set tape[p+1] 'a'
+loop tape[p] (
+    call shout
+    incr tape[p+1]
+    decr tape[p]
+)
+getch tape[p+2]
+putnum tape[p+2]
+";
+
+/// Saves the `Vm` to bytes and resumes a new one from them
+fn reload(vm: &Vm, limits: Limits) -> Vm {
+    let bytes = vm.snapshot().to_bytes();
+    Vm::from_snapshot(Snapshot::from_bytes(&bytes).unwrap(), limits)
+}
+
+#[test]
+fn round_trips_through_bytes() {
+    let snapshot = Snapshot {
+        tapes: vec![Some(vec![1, 2, 3]), None, Some(vec![])],
+        indices: vec![2, 0, 7],
+        ip: 12,
+        call_stack: vec![4, 9],
+        executed: 1234,
+        elapsed: Duration::new(3, 141_592_653),
+        output_len: 42,
+        input: b"rest".to_vec(),
+        input_closed: true,
+        eof: Eof::MinusOne,
+        captures: vec![b"outer".to_vec(), vec![]],
+    };
+    let bytes = snapshot.to_bytes();
+    assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot));
+
+    for eof in [Eof::Zero, Eof::MinusOne, Eof::Unchanged, Eof::Error] {
+        let snapshot = Snapshot {
+            tapes: vec![],
+            indices: vec![],
+            ip: 0,
+            call_stack: vec![],
+            executed: 0,
+            elapsed: Duration::ZERO,
+            output_len: 0,
+            input: vec![],
+            input_closed: false,
+            eof,
+            captures: vec![],
+        };
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+    }
+}
+
+#[test]
+fn rejects_corrupted_bytes() {
+    let mut vm = Vm::new();
+    vm.run_for(&compile(SOURCE), 20);
+    let bytes = vm.snapshot().to_bytes();
+
+    assert_eq!(
+        Snapshot::from_bytes(b"NOTASNAP"),
+        Err("Not a tape snapshot")
+    );
+    for len in 0..bytes.len() {
+        assert!(Snapshot::from_bytes(&bytes[..len]).is_err(), "{}", len);
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(Snapshot::from_bytes(&trailing), Err("Corrupted snapshot"));
+
+    // the last bytes are the EOF mode, whether input is closed and no captures
+    let eof = bytes.len() - 9;
+    let mut bad_eof = bytes.clone();
+    bad_eof[eof] = 4;
+    assert_eq!(Snapshot::from_bytes(&bad_eof), Err("Corrupted snapshot"));
+    let mut bad_closed = bytes.clone();
+    bad_closed[eof - 1] = 2;
+    assert_eq!(Snapshot::from_bytes(&bad_closed), Err("Corrupted snapshot"));
+}
+
+#[test]
+fn resumes_after_every_step() {
+    let program = compile(SOURCE);
+    let mut uninterrupted = Vm::new();
+    uninterrupted.push_input(b"7");
+    uninterrupted.close_input();
+    assert_eq!(uninterrupted.run_for(&program, u64::MAX), VmState::Halted);
+    let expected = uninterrupted.take_output();
+
+    let mut vm = Vm::new();
+    vm.push_input(b"7");
+    vm.close_input();
+    let mut output = vec![];
+    loop {
+        let state = vm.step(&program);
+        output.extend(vm.take_output());
+        if state != VmState::Running {
+            assert_eq!(state, VmState::Halted);
+            break;
+        }
+        vm = reload(&vm, Limits::default());
+    }
+    assert_eq!(output, expected);
+    assert_eq!(vm.executed(), uninterrupted.executed());
+}
+
+#[test]
+fn resumes_waiting_for_input() {
+    let program = compile(SOURCE);
+    let mut vm = Vm::new();
+    assert_eq!(vm.run_for(&program, u64::MAX), VmState::NeedsInput);
+    let mut output = vm.take_output();
+
+    let mut vm = reload(&vm, Limits::default());
+    assert_eq!(vm.run_for(&program, u64::MAX), VmState::NeedsInput);
+    vm.push_input(b"A");
+    assert_eq!(vm.run_for(&program, u64::MAX), VmState::Halted);
+    output.extend(vm.take_output());
+    assert_eq!(output, b"aabbccddee65");
+}
+
+#[test]
+fn keeps_the_end_of_input() {
+    let program = compile(SOURCE);
+    let mut vm = Vm::new();
+    vm.set_eof(Eof::Zero);
+    vm.close_input();
+    vm.run_for(&program, 10);
+
+    let mut vm = reload(&vm, Limits::default());
+    assert_eq!(vm.run_for(&program, u64::MAX), VmState::Halted);
+    assert!(vm.take_output().ends_with(b"0"));
+}
+
+#[test]
+fn keeps_charging_limits() {
+    let program = compile(SOURCE);
+    let limits = Limits {
+        max_output: Some(6),
+        ..Limits::default()
+    };
+    let mut vm = Vm::with_limits(limits);
+    vm.close_input();
+    // far enough to have printed "aabb"
+    while vm.snapshot().output_len < 4 {
+        assert_eq!(vm.step(&program), VmState::Running);
+    }
+
+    let mut vm = reload(&vm, limits);
+    assert_eq!(
+        vm.run_for(&program, u64::MAX),
+        VmState::Error(limits::OUTPUT_TOO_LARGE)
+    );
+    assert_eq!(vm.take_output(), b"cc");
+    assert_eq!(vm.snapshot().output_len, 6);
+
+    // resumed with less room than was already printed
+    let program = compile("print \"hello\"\nprint \"!\"\n");
+    let mut vm = Vm::new();
+    while vm.snapshot().output_len < 5 {
+        assert_eq!(vm.step(&program), VmState::Running);
+    }
+    let limits = Limits {
+        max_output: Some(2),
+        ..Limits::default()
+    };
+    let mut vm = reload(&vm, limits);
+    assert_eq!(
+        vm.run_for(&program, u64::MAX),
+        VmState::Error(limits::OUTPUT_TOO_LARGE)
+    );
+    assert_eq!(vm.take_output(), b"");
+
+    let mut vm = Vm::new();
+    vm.run_for(&program, 10);
+    let mut snapshot = vm.snapshot();
+    snapshot.elapsed = Duration::from_secs(5);
+    let limits = Limits {
+        timeout: Some(Duration::from_secs(5)),
+        ..Limits::default()
+    };
+    let mut vm = Vm::from_snapshot(snapshot, limits);
+    assert_eq!(
+        vm.run_for(&program, u64::MAX),
+        VmState::Error(limits::TIMED_OUT)
+    );
+}
+
+#[test]
+fn fails_instead_of_panicking_on_missing_tapes() {
+    let program = compile(SOURCE);
+    let mut vm = Vm::new();
+    vm.run_for(&program, 10);
+    let mut snapshot = vm.snapshot();
+    // as if the tapes were never made
+    snapshot.tapes = vec![None];
+    let mut vm = Vm::from_snapshot(snapshot, Limits::default());
+    assert_eq!(
+        vm.run_for(&program, u64::MAX),
+        VmState::Error("Index out of range")
+    );
+}
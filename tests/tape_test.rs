@@ -0,0 +1,57 @@
+//! Runs `tape test` on the standard library's tests and on the fixtures in
+//! `tests/test_runner/`.
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn tape_test(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tape"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .arg("test")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn stdlib_tests_pass() {
+    let output = tape_test(&["std"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("test std/io_test.tp ... ok"), "{}", stdout);
+    assert!(stdout.contains("test std/mem_test.tp ... ok"), "{}", stdout);
+}
+
+#[test]
+fn reports_failures_with_their_location() {
+    let output = tape_test(&["tests/test_runner"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    let failing = Path::new("tests/test_runner/nested/failing_test.tp").display();
+    for expected in [
+        "running 2 tests".to_owned(),
+        format!("test {} ... FAILED", failing),
+        "test tests/test_runner/passing_test.tp ... ok".to_owned(),
+        format!(
+            "{}:5: Assertion failed\n    assert t[p] == 7\nfound 6",
+            failing
+        ),
+        "test result: FAILED. 1 passed; 1 failed".to_owned(),
+    ] {
+        assert!(
+            stdout.contains(&expected),
+            "no {:?} in\n{}",
+            expected,
+            stdout
+        );
+    }
+    assert!(!stdout.contains("endless"));
+}
+
+#[test]
+fn fuel_stops_tests_that_never_end() {
+    let output = tape_test(&["--fuel", "100", "tests/test_runner/endless.tp"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(stdout.contains("Limit exceeded"), "{}", stdout);
+}
@@ -0,0 +1,39 @@
+//! `--timeout` takes any finite number of seconds that is not negative.
+
+use std::process::{Command, Output};
+
+fn tape(timeout: &str, jit: bool) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_tape"));
+    if jit {
+        command.arg("--jit");
+    }
+    command
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["--timeout", timeout, "tests/test_runner/endless.tp"])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn rejects_durations_out_of_range() {
+    for timeout in ["1e30", "inf", "-1", "NaN", "soon"] {
+        let output = tape(timeout, false);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(2), "{}: {}", timeout, stderr);
+        assert!(
+            stderr.contains(&format!("invalid value '{}' for '--timeout", timeout)),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn stops_endless_programs() {
+    for jit in [false, true] {
+        let output = tape("0.1", jit);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.contains("Limit exceeded: timed out"), "{}", stderr);
+    }
+}
@@ -0,0 +1,240 @@
+//! Drives `Vm::run_async` by hand, polling it like an executor would, to see
+//! when it waits on input and when it yields.
+
+mod common;
+
+use common::compile;
+use futures_io::AsyncWrite;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use tape::compiler::Compiler;
+use tape::limits::{self, Limits};
+use tape::vm::{Vm, END_OF_INPUT};
+use tape::vm_async::input_channel;
+
+/// Counts how often the task was woken
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl CountingWaker {
+    fn wakes(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Collects what the program prints
+#[derive(Default)]
+struct Output(Vec<u8>);
+
+impl AsyncWrite for Output {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A future polled by hand, and how it wakes
+struct Task<F> {
+    future: Pin<Box<F>>,
+    waker: Arc<CountingWaker>,
+}
+
+impl<F: Future> Task<F> {
+    fn new(future: F) -> Self {
+        Task {
+            future: Box::pin(future),
+            waker: Arc::default(),
+        }
+    }
+
+    fn poll(&mut self) -> Poll<F::Output> {
+        let waker = Waker::from(self.waker.clone());
+        self.future.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+
+    /// Polls while the future wakes itself up, returning how many times it
+    /// did, and its output if it got that far
+    fn run_until_stalled(&mut self) -> (usize, Option<F::Output>) {
+        let mut yields = 0;
+        loop {
+            let wakes = self.waker.wakes();
+            match self.poll() {
+                Poll::Ready(output) => return (yields, Some(output)),
+                Poll::Pending if self.waker.wakes() > wakes => yields += 1,
+                Poll::Pending => return (yields, None),
+            }
+        }
+    }
+}
+
+const COUNTDOWN: &str = "make tape[2]
+make p: idx
+set tape[p] 200
+loop tape[p] (
+    decr tape[p]
+)
+print \"done\"
+";
+
+#[test]
+fn waits_for_input_until_fed() {
+    let program = compile(
+        "make tape[1]
+make p: idx
+getch tape[p]
+loop tape[p] (
+    putch tape[p]
+    getch tape[p]
+)
+",
+    );
+    let mut vm = Vm::new();
+    let (sender, input) = input_channel();
+    let mut output = Output::default();
+    let mut task = Task::new(vm.run_async(&program, input, &mut output, 1_000));
+
+    assert!(task.run_until_stalled().1.is_none());
+    let wakes = task.waker.wakes();
+    sender.send(b"hi");
+    assert!(task.waker.wakes() > wakes, "sending input wakes the task");
+    assert!(task.run_until_stalled().1.is_none());
+
+    sender.send(b"!\0");
+    let (_, result) = task.run_until_stalled();
+    assert_eq!(result, Some(Ok(())));
+    drop(task);
+    assert_eq!(output.0, b"hi!");
+}
+
+#[test]
+fn ends_input_when_the_sender_is_dropped() {
+    let program = compile(
+        "make tape[1]
+make p: idx
+getch tape[p]
+putnum tape[p]
+",
+    );
+    let mut vm = Vm::new();
+    let (sender, input) = input_channel();
+    let mut output = Output::default();
+    let mut task = Task::new(vm.run_async(&program, input, &mut output, 1_000));
+
+    assert!(task.run_until_stalled().1.is_none());
+    drop(sender);
+    let (_, result) = task.run_until_stalled();
+    assert_eq!(result, Some(Err(END_OF_INPUT)));
+}
+
+#[test]
+fn yields_every_few_instructions() {
+    let program = compile(COUNTDOWN);
+    let mut vm = Vm::new();
+    let (_sender, input) = input_channel();
+    let mut output = Output::default();
+    let mut task = Task::new(vm.run_async(&program, input, &mut output, 10));
+
+    let (yields, result) = task.run_until_stalled();
+    assert_eq!(result, Some(Ok(())));
+    drop(task);
+    let executed = vm.executed() as usize;
+    assert_eq!(yields, executed / 10, "{} instructions", executed);
+    assert_eq!(output.0, b"done");
+}
+
+#[test]
+fn yields_until_fuel_runs_out() {
+    let program = compile(COUNTDOWN);
+    let mut vm = Vm::with_limits(Limits {
+        fuel: Some(100),
+        ..Limits::default()
+    });
+    let (_sender, input) = input_channel();
+    let mut task = Task::new(vm.run_async(&program, input, Output::default(), 10));
+
+    let (yields, result) = task.run_until_stalled();
+    assert_eq!(result, Some(Err(limits::FUEL_EXHAUSTED)));
+    assert_eq!(yields, 10);
+    drop(task);
+    assert_eq!(vm.executed(), 100);
+}
+
+#[test]
+fn prints_what_run_prints() {
+    for name in ["io/echo", "io/numbers", "control/procs", "macros/stdlib"] {
+        let path = format!("{}/tests/programs/{}.tp", env!("CARGO_MANIFEST_DIR"), name);
+        let input = fs::read(format!(
+            "{}/tests/programs/{}.input",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap_or_default();
+
+        // `tape` runs programs with `Vm::run`
+        let mut child = Command::new(env!("CARGO_BIN_EXE_tape"))
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // a program may end without reading all of its input
+        let _ = child.stdin.take().unwrap().write_all(&input);
+        let expected = child.wait_with_output().unwrap();
+
+        let mut compiler = Compiler::new(&fs::read_to_string(&path).unwrap());
+        compiler.path = Some(path.into());
+        let program = compiler.try_compile().unwrap();
+        let mut vm = Vm::new();
+        let (sender, channel) = input_channel();
+        let mut output = Output::default();
+        let mut task = Task::new(vm.run_async(&program, channel, &mut output, 7));
+        // fed a byte at a time, so the program waits on input over and over,
+        // though it may end before reading all of it
+        let mut result = None;
+        for &byte in &input {
+            result = task.run_until_stalled().1;
+            if result.is_some() {
+                break;
+            }
+            sender.send(&[byte]);
+        }
+        drop(sender);
+        let result = result.or_else(|| task.run_until_stalled().1);
+        assert_eq!(
+            result.map(|result| result.is_ok()),
+            Some(expected.status.success()),
+            "{}",
+            name
+        );
+        drop(task);
+        assert_eq!(output.0, expected.stdout, "{}", name);
+    }
+}